@@ -1,72 +1,267 @@
-use std::time::{SystemTime, Duration};
-use std::collections::{LinkedList};
+use std::time::{Duration, Instant};
+use std::collections::HashMap;
+use std::cmp::max;
 
+const SLOT_BITS: usize = 6;
+const SLOT_COUNT: usize = 1 << SLOT_BITS;
+const SLOT_MASK: u64 = (SLOT_COUNT - 1) as u64;
+
+// Identifies an element for cancellation
+#[derive(PartialEq, Eq, Hash, Copy, Clone, Debug)]
+pub struct ExpiryHandle(u64);
+
+// Hierarchical timer wheels on the monotonic clock. The first wheel has one slot per tick, every further wheel
+// covers a whole rotation of the previous one per slot. Its elements are moved down once their slot is due.
 pub struct ExpiryHandler<T> {
-    buckets: Vec<LinkedList<T>>,
-    start_time: SystemTime,
-    bucket_seconds: usize,
-    maximum_duration: Duration,
-    minimum_duration: Duration,
-    last_cleaned: SystemTime,
-    clean_next: SystemTime,
-    clean_next_bucket: usize,
-    bucket_count: usize
+    wheels: Vec<Vec<Vec<u64>>>, // Element IDs by wheel and slot
+    elements: HashMap<u64, (T, u64)>, // Elements with their deadline tick by ID
+    start_time: Instant,
+    resolution: Duration, // Duration of a tick
+    current: u64, // Last tick which has been cleaned
+    next_id: u64
+}
+
+fn nanos(duration: Duration) -> u64 {
+    duration.as_secs().saturating_mul(1_000_000_000).saturating_add(duration.subsec_nanos() as u64)
+}
+
+// Digits of a tick above the given number of bits
+fn upper(tick: u64, bits: usize) -> u64 {
+    tick.checked_shr(bits as u32).unwrap_or(0)
 }
 
 impl<T> ExpiryHandler<T> {
-    pub fn new(bucket_count: usize, bucket_seconds: usize) -> Self {
-        let now = SystemTime::now();
-        let minimum_duration = Duration::from_secs(bucket_seconds as u64);
-        let mut result = ExpiryHandler {
-            buckets: Vec::with_capacity(bucket_count),
-            start_time: now,
-            bucket_seconds: bucket_seconds,
-            maximum_duration: Duration::from_secs((bucket_count * bucket_seconds - 1) as u64),
-            minimum_duration: minimum_duration,
-            last_cleaned: now,
-            clean_next: now + minimum_duration,
-            clean_next_bucket: 0,
-            bucket_count: bucket_count
-        };
-        for _ in 0..bucket_count {
-            result.buckets.push(LinkedList::new());
+    pub fn new(resolution: Duration) -> Self {
+        ExpiryHandler {
+            wheels: Vec::new(),
+            elements: HashMap::new(),
+            start_time: Instant::now(),
+            resolution: if resolution > Duration::from_secs(0) { resolution } else { Duration::from_millis(1) },
+            current: 0,
+            next_id: 0
         }
-        result
     }
 
-    pub fn add(&mut self, element: T, lifetime: Duration) {
-        let mut duration = lifetime;
-        if duration > self.maximum_duration {
-            duration = self.maximum_duration;
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+
+    // Number of ticks since the start, rounded down
+    fn ticks(&self, instant: Instant) -> u64 {
+        nanos(instant.duration_since(self.start_time)) / nanos(self.resolution)
+    }
+
+    // The element expires once the lifetime is over, rounded up to the next tick
+    pub fn add(&mut self, element: T, lifetime: Duration) -> ExpiryHandle {
+        self.add_at(element, lifetime, Instant::now())
+    }
+
+    fn add_at(&mut self, element: T, lifetime: Duration, now: Instant) -> ExpiryHandle {
+        let resolution = nanos(self.resolution);
+        let elapsed = nanos(now.duration_since(self.start_time)).saturating_add(nanos(lifetime));
+        let deadline = max(elapsed / resolution + if elapsed % resolution > 0 { 1 } else { 0 }, self.current + 1);
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.elements.insert(id, (element, deadline));
+        self.place(id, deadline);
+        ExpiryHandle(id)
+    }
+
+    // Remove an element before it expires. Returns None if it has already expired or been cancelled.
+    pub fn cancel(&mut self, handle: ExpiryHandle) -> Option<T> {
+        // The ID is left in its slot and skipped once the slot is due
+        self.elements.remove(&handle.0).map(|(element, _)| element)
+    }
+
+    // Put an element into the lowest wheel whose rotation it does not outlast. Its slot is the first one
+    // of that wheel which is due at or before the deadline.
+    fn place(&mut self, id: u64, deadline: u64) {
+        let mut level = 0;
+        while upper(deadline, SLOT_BITS * (level + 1)) != upper(self.current, SLOT_BITS * (level + 1)) {
+            level += 1;
         }
-        if duration < self.minimum_duration {
-            duration = self.minimum_duration;
+        while self.wheels.len() <= level {
+            self.wheels.push((0..SLOT_COUNT).map(|_| Vec::new()).collect());
         }
-        let elapsed_secs = (SystemTime::now().duration_since(self.start_time).unwrap()
-            + duration).as_secs() as usize;
-        let bucket = (elapsed_secs / self.bucket_seconds) % self.bucket_count;
-        self.buckets[bucket].push_back(element);
+        let slot = ((deadline >> (SLOT_BITS * level)) & SLOT_MASK) as usize;
+        self.wheels[level][slot].push(id);
     }
 
+    // Pass the elements which have expired since the last call to the function and remove them
     pub fn clean(&mut self, fun: &mut FnMut(&T)) {
-        let now = SystemTime::now();
-        if now < self.clean_next {
-            return;
+        self.clean_at(Instant::now(), fun);
+    }
+
+    fn clean_at(&mut self, now: Instant, fun: &mut FnMut(&T)) {
+        let target = self.ticks(now);
+        if self.elements.is_empty() && self.current < target {
+            // Nothing to do for the ticks in between
+            for wheel in self.wheels.iter_mut() {
+                for slot in wheel.iter_mut() {
+                    slot.clear();
+                }
+            }
+            self.current = target;
+        }
+        while self.current < target {
+            self.current += 1;
+            // Move the elements of the higher wheels down whose slot begins with this tick
+            for level in (1..self.wheels.len()).rev() {
+                if self.current & ((1 << (SLOT_BITS * level)) - 1) != 0 {
+                    continue;
+                }
+                let slot = ((self.current >> (SLOT_BITS * level)) & SLOT_MASK) as usize;
+                let ids: Vec<u64> = self.wheels[level][slot].drain(..).collect();
+                for id in ids {
+                    let deadline = match self.elements.get(&id) {
+                        Some(&(_, deadline)) => deadline,
+                        None => continue
+                    };
+                    if deadline <= self.current {
+                        self.wheels[0][(self.current & SLOT_MASK) as usize].push(id);
+                    } else {
+                        self.place(id, deadline);
+                    }
+                }
+            }
+            if self.wheels.is_empty() {
+                continue;
+            }
+            let slot = (self.current & SLOT_MASK) as usize;
+            let ids: Vec<u64> = self.wheels[0][slot].drain(..).collect();
+            for id in ids {
+                if let Some((element, _)) = self.elements.remove(&id) {
+                    fun(&element);
+                }
+            }
         }
-        let mut buckets_to_clean = (now.duration_since(self.last_cleaned).unwrap().as_secs() as usize) / self.bucket_seconds;
-        if buckets_to_clean > self.bucket_count {
-            buckets_to_clean = self.bucket_count;
+    }
+
+    // Time until the next slot with elements is due. The elements of a slot in a higher wheel are only due
+    // at or after its start, so the time is a lower bound then.
+    pub fn next_expiry(&self) -> Option<Duration> {
+        self.next_expiry_at(Instant::now())
+    }
+
+    fn next_expiry_at(&self, now: Instant) -> Option<Duration> {
+        if self.elements.is_empty() {
+            return None;
         }
-        for i in 0..buckets_to_clean {
-            let ref mut bucket = self.buckets[(self.clean_next_bucket + i) % self.bucket_count];
-            for j in bucket.iter() {
-                fun(&j);
+        for (level, wheel) in self.wheels.iter().enumerate() {
+            let shift = SLOT_BITS * level;
+            let digit = ((self.current >> shift) & SLOT_MASK) as usize;
+            for slot in (digit + 1)..SLOT_COUNT {
+                if !wheel[slot].is_empty() {
+                    let rotation = upper(self.current, shift + SLOT_BITS).checked_shl((shift + SLOT_BITS) as u32).unwrap_or(0);
+                    let tick = rotation | ((slot as u64) << shift);
+                    let due = nanos(self.resolution).saturating_mul(tick);
+                    let elapsed = nanos(now.duration_since(self.start_time));
+                    let remaining = due.saturating_sub(elapsed);
+                    return Some(Duration::new(remaining / 1_000_000_000, (remaining % 1_000_000_000) as u32));
+                }
             }
-            bucket.clear();
         }
-        self.clean_next_bucket = (self.clean_next_bucket + buckets_to_clean) % self.bucket_count;
-        self.last_cleaned = self.start_time + Duration::from_secs((((now.duration_since(self.start_time).unwrap().as_secs() as usize)
-            / self.bucket_seconds) * self.bucket_seconds) as u64);
-        self.clean_next = self.last_cleaned + self.minimum_duration;
+        Some(self.resolution)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expired(handler: &mut ExpiryHandler<u64>, ms: u64) -> Vec<u64> {
+        let now = handler.start_time + Duration::from_millis(ms);
+        let mut result = Vec::new();
+        handler.clean_at(now, &mut |e: &u64| result.push(*e));
+        result.sort();
+        result
+    }
+
+    #[test]
+    fn slot_overflow() {
+        let mut handler = ExpiryHandler::new(Duration::from_millis(1));
+        let start = handler.start_time;
+        // Beyond the first wheel (64 ticks) and the second one (4096 ticks)
+        for &ms in [10u64, 63, 64, 65, 200, 4095, 4096, 5000].iter() {
+            handler.add_at(ms, Duration::from_millis(ms), start);
+        }
+        assert_eq!(handler.len(), 8);
+        assert_eq!(expired(&mut handler, 9), vec![]);
+        assert_eq!(expired(&mut handler, 10), vec![10]);
+        assert_eq!(expired(&mut handler, 64), vec![63, 64]);
+        assert_eq!(expired(&mut handler, 199), vec![65]);
+        assert_eq!(expired(&mut handler, 4095), vec![200, 4095]);
+        assert_eq!(expired(&mut handler, 4999), vec![4096]);
+        assert_eq!(expired(&mut handler, 5000), vec![5000]);
+        assert!(handler.is_empty());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn cascading() {
+        let mut handler = ExpiryHandler::new(Duration::from_millis(1));
+        let start = handler.start_time;
+        handler.add_at(70, Duration::from_millis(70), start);
+        handler.add_at(4100, Duration::from_millis(4100), start);
+        // Advance tick by tick so that every element is moved down through the wheels
+        for ms in 1..4100 {
+            let result = expired(&mut handler, ms);
+            if ms == 70 {
+                assert_eq!(result, vec![70]);
+            } else {
+                assert_eq!(result, vec![]);
+            }
+        }
+        assert_eq!(expired(&mut handler, 4100), vec![4100]);
+
+        // Elements added later relative to the current tick
+        let now = start + Duration::from_millis(4100);
+        handler.add_at(1, Duration::from_millis(100), now);
+        assert_eq!(expired(&mut handler, 4199), vec![]);
+        assert_eq!(expired(&mut handler, 4200), vec![1]);
+    }
+
+    #[test]
+    fn rounded_up() {
+        let mut handler = ExpiryHandler::new(Duration::from_millis(10));
+        let start = handler.start_time;
+        handler.add_at(1, Duration::from_millis(15), start);
+        handler.add_at(2, Duration::from_millis(0), start);
+        assert_eq!(expired(&mut handler, 19), vec![2]);
+        assert_eq!(expired(&mut handler, 20), vec![1]);
+    }
+
+    #[test]
+    fn cancel() {
+        let mut handler = ExpiryHandler::new(Duration::from_millis(1));
+        let start = handler.start_time;
+        let first = handler.add_at(1, Duration::from_millis(100), start);
+        let second = handler.add_at(2, Duration::from_millis(100), start);
+        let third = handler.add_at(3, Duration::from_millis(5000), start);
+        assert_eq!(handler.cancel(first), Some(1));
+        assert_eq!(handler.cancel(first), None);
+        assert_eq!(handler.cancel(third), Some(3));
+        assert_eq!(handler.len(), 1);
+        assert_eq!(expired(&mut handler, 10000), vec![2]);
+        // Expired elements cannot be cancelled anymore
+        assert_eq!(handler.cancel(second), None);
+        assert!(handler.is_empty());
+    }
+
+    #[test]
+    fn next_expiry_lower_bound() {
+        let mut handler = ExpiryHandler::new(Duration::from_millis(1));
+        let start = handler.start_time;
+        assert_eq!(handler.next_expiry_at(start), None);
+        // Due within the slot of the second wheel which starts at tick 2944
+        handler.add_at(1, Duration::from_millis(3000), start);
+        assert_eq!(handler.next_expiry_at(start), Some(Duration::from_millis(2944)));
+        assert_eq!(expired(&mut handler, 2944), vec![]);
+        assert_eq!(handler.next_expiry_at(start + Duration::from_millis(2944)), Some(Duration::from_millis(56)));
+        handler.add_at(2, Duration::from_millis(5), start + Duration::from_millis(2944));
+        assert_eq!(handler.next_expiry_at(start + Duration::from_millis(2944)), Some(Duration::from_millis(5)));
+    }
+}
@@ -0,0 +1,80 @@
+// Conversion of internationalized domain names between U-labels and A-labels (UTS #46, RFC 3492)
+
+extern crate idna as uts46;
+
+use self::uts46::uts46::{Flags, to_ascii as uts46_to_ascii, to_unicode as uts46_to_unicode};
+
+// Nontransitional processing keeps deviation characters such as ß, which registries like DENIC accept.
+// STD3 rules are not enforced because some registered names contain underscores.
+static FLAGS: Flags = Flags {
+    use_std3_ascii_rules: false,
+    transitional_processing: false,
+    verify_dns_length: true
+};
+
+// Map and normalize a domain name (case folding, NFC, fullwidth forms) and convert all labels into A-labels.
+// Returns None if the name is invalid, e.g. because of disallowed characters or overlong labels.
+pub fn to_ascii(domain: &str) -> Option<String> {
+    uts46_to_ascii(domain, FLAGS).ok()
+}
+
+// Convert all A-labels of a domain name into U-labels. Labels which cannot be decoded are kept.
+pub fn to_unicode(domain: &str) -> String {
+    let labels: Vec<String> = domain.split('.').map(|label| {
+        match uts46_to_unicode(label, FLAGS) {
+            (unicode, Ok(())) => unicode,
+            _ => String::from(label)
+        }
+    }).collect();
+    labels.join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ascii() {
+        assert_eq!(to_ascii("example.com"), Some(String::from("example.com")));
+        assert_eq!(to_ascii("Example.COM"), Some(String::from("example.com")));
+        assert_eq!(to_ascii("müller.de"), Some(String::from("xn--mller-kva.de")));
+        assert_eq!(to_ascii("MÜLLER.de"), Some(String::from("xn--mller-kva.de")));
+        assert_eq!(to_ascii("bücher.example"), Some(String::from("xn--bcher-kva.example")));
+        assert_eq!(to_ascii("例え.jp"), Some(String::from("xn--r8jz45g.jp")));
+        // RFC 3492, section 7.1
+        assert_eq!(to_ascii("他们为什么不说中文"), Some(String::from("xn--ihqwcrb4cv8a8dqg056pqjye")));
+        assert_eq!(to_ascii("ひとつ屋根の下2"), Some(String::from("xn--2-u9tlzr9756bt3uc0v")));
+    }
+
+    #[test]
+    fn normalization() {
+        // Decomposed umlaut (NFC)
+        assert_eq!(to_ascii("mu\u{308}ller.de"), Some(String::from("xn--mller-kva.de")));
+        // Fullwidth forms and the ideographic full stop
+        assert_eq!(to_ascii("ｅｘａｍｐｌｅ．ｃｏｍ"), Some(String::from("example.com")));
+        assert_eq!(to_ascii("例え\u{3002}jp"), Some(String::from("xn--r8jz45g.jp")));
+        // Deviation characters are kept (nontransitional processing)
+        assert_eq!(to_ascii("faß.de"), Some(String::from("xn--fa-hia.de")));
+        assert_eq!(to_ascii("ＡＢＣ・日本.co.jp"), Some(String::from("xn--abc-rs4b422ycvb.co.jp")));
+    }
+
+    #[test]
+    fn invalid() {
+        // Leading combining mark
+        assert_eq!(to_ascii("\u{308}a.de"), None);
+        // Overlong label and empty label
+        assert_eq!(to_ascii(&(String::from("a").repeat(64) + ".de")), None);
+        assert_eq!(to_ascii("example..com"), None);
+        // Malformed A-label
+        assert_eq!(to_ascii("xn--a.de"), None);
+    }
+
+    #[test]
+    fn unicode() {
+        assert_eq!(to_unicode("xn--mller-kva.de"), "müller.de");
+        assert_eq!(to_unicode("XN--MLLER-KVA.de"), "müller.de");
+        assert_eq!(to_unicode("xn--r8jz45g.jp"), "例え.jp");
+        assert_eq!(to_unicode("example.com"), "example.com");
+        assert_eq!(to_unicode("xn--a.de"), "xn--a.de");
+    }
+}
@@ -1,77 +1,176 @@
 pub mod expiry;
+pub mod idna;
 
 extern crate trust_dns;
 extern crate mio;
+extern crate rand;
 
-use std::collections::{HashMap, LinkedList, VecDeque};
-use std::time::{SystemTime, Duration};
-use std::cmp::Eq;
+use std::collections::{HashMap, BTreeMap, LinkedList, VecDeque};
+use std::time::{SystemTime, Instant, Duration, UNIX_EPOCH};
+use std::cmp::{Eq, min, max};
 use std::hash::Hash;
-use std::io::{BufReader, BufRead};
+use std::io;
+use std::io::{BufReader, BufRead, BufWriter, Read, Write, ErrorKind};
 use std::fs::File;
 use std::net::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr};
+use std::fmt::Write as FmtWrite;
 use std::str::FromStr;
 use std::rc::Rc;
-use masswhois::{IpConfig, IP_V4, IP_V6};
-use self::expiry::{ExpiryHandler};
+use self::expiry::{ExpiryHandler, ExpiryHandle};
 use self::trust_dns::op::{Message, Query};
 use self::trust_dns::rr::domain::Name;
-use self::trust_dns::rr::record_type::RecordType;
+pub use self::trust_dns::rr::record_type::RecordType;
 use self::trust_dns::op::header::MessageType;
+use self::trust_dns::op::Edns;
 use self::trust_dns::rr::record_data::RData;
 use std::marker::Copy;
-use mio::{Poll, PollOpt, Token, Ready};
-use mio::net::UdpSocket;
+use self::mio::{Poll, PollOpt, Token, Ready};
+use self::mio::net::{TcpStream, UdpSocket};
 
-enum ExpiryRef<T> {
-    Positive(Rc<T>, usize),
-    Negative(Rc<T>)
+static CNAME_CHAIN_LIMIT: usize = 16;
+static NEGATIVE_TTL_FALLBACK: u64 = 60; // Used for negative responses without SOA record
+static MAX_BACKOFF_DOUBLINGS: usize = 6;
+static RETRANSMIT_RESOLUTION_MS: u64 = 10;
+static CACHE_TIMER_RESOLUTION_SECS: u64 = 1;
+static CACHE_MINIMUM_TTL_SECS: u64 = 1; // Long enough for waiting tokens to pick up the entry
+static UDP_TOKEN_COUNT: usize = 2; // Tokens after these belong to TCP connections
+static EDNS_PAYLOAD_DEFAULT: u16 = 1232; // Avoids fragmentation on common paths
+
+// Response codes, including the extended ones of RFC 6891
+static RCODE_NOERROR: u16 = 0;
+static RCODE_FORMERR: u16 = 1;
+static RCODE_NXDOMAIN: u16 = 3;
+static RCODE_NOTIMP: u16 = 4;
+static RCODE_BADVERS: u16 = 16;
+
+bitflags! {
+    pub struct IpVersion: u8 {
+        const IP_V4 = 1;
+        const IP_V6 = 2;
+    }
 }
 
-static CNAME_CHAIN_LIMIT: usize = 16;
+#[derive(Copy, Clone)]
+pub struct IpConfig {
+    pub default_version: IpVersion,
+    pub supported_versions: IpVersion
+}
 
-impl<T> ExpiryRef<T> {
-    pub fn get_rc(&self) -> Rc<T> {
-        match self {
-            &ExpiryRef::Negative(ref rc) => {
-                rc.clone()
-            },
-            &ExpiryRef::Positive(ref rc, _) => {
-                rc.clone()
-            }
-        }
+pub enum ResolvePromise<T> {
+    Resolved(T, Addresses),
+    Resolving
+}
+
+// Addresses of a name by IP version, empty if the name could not be resolved
+#[derive(Clone, Debug, Default)]
+pub struct Addresses {
+    pub ipv4: Vec<IpAddr>,
+    pub ipv6: Vec<IpAddr>
+}
+
+impl Addresses {
+    pub fn is_empty(&self) -> bool {
+        self.ipv4.is_empty() && self.ipv6.is_empty()
     }
 
-    pub fn is_negative(&self) -> bool {
-        match self {
-            &ExpiryRef::Negative(_) => {
-                true
-            },
-            &ExpiryRef::Positive(_, _) => {
-                false
-            }
+    // All addresses, alternating between the versions starting with the default one (RFC 8305, section 4)
+    pub fn ordered(&self, ip_config: &IpConfig) -> Vec<IpAddr> {
+        let (preferred, secondary) = if ip_config.default_version == IP_V6 {
+            (&self.ipv6, &self.ipv4)
+        } else {
+            (&self.ipv4, &self.ipv6)
+        };
+        let mut result = Vec::with_capacity(preferred.len() + secondary.len());
+        for i in 0..max(preferred.len(), secondary.len()) {
+            result.extend(preferred.get(i));
+            result.extend(secondary.get(i));
         }
+        result
     }
+}
 
-    pub fn is_positive(&self) -> bool {
-        !self.is_negative()
-    }
+pub enum LookupPromise<T> {
+    Answered(T, Answer),
+    Pending
+}
 
-    pub fn get_index(&self) -> usize {
-        match self {
-            &ExpiryRef::Positive(_, size) => {
-                size
-            },
-            _ => {
-                panic!("ExpiryRef not positive.");
-            }
+// Passed along with every waiting token to the function given to receive or expire
+pub enum Completion {
+    Resolved(Addresses), // Addresses of a name as query would return them now
+    Answered(Answer) // Records of a lookup
+}
+
+// Typed record data of a lookup, names are absolute
+#[derive(Clone, Debug, PartialEq)]
+pub enum Record {
+    A(Ipv4Addr),
+    AAAA(Ipv6Addr),
+    CNAME(String),
+    NS(String),
+    PTR(String),
+    MX { preference: u16, exchange: String },
+    TXT(Vec<String>),
+    SOA { mname: String, rname: String, serial: u32, refresh: i32, retry: i32, expire: i32, minimum: u32 }
+}
+
+impl Record {
+    // Names are lowercase, compressed names in responses may carry the 0x20 encoding of the question
+    fn from_rdata(rdata: &RData) -> Option<Record> {
+        match *rdata {
+            RData::A(ip) => Some(Record::A(ip)),
+            RData::AAAA(ip) => Some(Record::AAAA(ip)),
+            RData::CNAME(ref name) => Some(Record::CNAME(name.to_string().to_lowercase())),
+            RData::NS(ref name) => Some(Record::NS(name.to_string().to_lowercase())),
+            RData::PTR(ref name) => Some(Record::PTR(name.to_string().to_lowercase())),
+            RData::MX(ref mx) => Some(Record::MX {
+                preference: mx.preference(),
+                exchange: mx.exchange().to_string().to_lowercase()
+            }),
+            RData::TXT(ref txt) => Some(Record::TXT(txt.txt_data().to_vec())),
+            RData::SOA(ref soa) => Some(Record::SOA {
+                mname: soa.mname().to_string().to_lowercase(),
+                rname: soa.rname().to_string().to_lowercase(),
+                serial: soa.serial(),
+                refresh: soa.refresh(),
+                retry: soa.retry(),
+                expire: soa.expire(),
+                minimum: soa.minimum()
+            }),
+            _ => None
         }
     }
 }
 
-pub enum ResolvePromise<T> {
-    Resolved(T, Option<IpAddr>),
-    Resolving
+#[derive(Clone, Debug, PartialEq)]
+pub enum Answer {
+    Records(Vec<Record>), // Empty if the name exists without records of the type (NODATA)
+    NonExistent, // NXDOMAIN
+    Failure // No server gave a usable response
+}
+
+// Time left until a deadline, zero once it has passed
+fn time_until(deadline: Instant, now: Instant) -> Duration {
+    if deadline > now { deadline - now } else { Duration::from_secs(0) }
+}
+
+// Name for reverse lookups of an address (PTR records)
+pub fn reverse_name(ip: &IpAddr) -> String {
+    let mut name = String::new();
+    match *ip {
+        IpAddr::V4(ip4) => {
+            for octet in ip4.octets().iter().rev() {
+                write!(name, "{}.", octet).unwrap();
+            }
+            name.push_str("in-addr.arpa.");
+        },
+        IpAddr::V6(ip6) => {
+            for octet in ip6.octets().iter().rev() {
+                write!(name, "{:x}.{:x}.", octet & 0x0F, octet >> 4).unwrap();
+            }
+            name.push_str("ip6.arpa.");
+        }
+    }
+    name
 }
 
 pub enum RoundRobin {
@@ -80,14 +179,67 @@ pub enum RoundRobin {
     Random
 }
 
-pub struct CachingResolver<'a, T> {
-    resolving: HashMap<(String, RecordType), (LinkedList<T>, usize)>,
-    encoding: HashMap<String, &'a String>, // 0x20 encoding
+// An outstanding query
+struct Resolution {
+    names: Vec<String>, // Names to try according to the search list
+    name_index: usize,
+    attempt: usize,
+    first_server: usize,
+    serial: usize, // Identifies the latest retransmission timer
+    tcp: bool, // Whether the query is sent over TCP because a response was truncated
+    edns: bool, // Cleared if a server does not understand the OPT record
+    transmissions: Vec<(u16, SocketAddr)> // IDs and servers of all attempts, responses must match one of them
+}
+
+impl Resolution {
+    fn new() -> Self {
+        Resolution {
+            names: Vec::new(),
+            name_index: 0,
+            attempt: 0,
+            first_server: 0,
+            serial: 0,
+            tcp: false,
+            edns: true,
+            transmissions: Vec::new()
+        }
+    }
+}
+
+// A query over TCP, the connection is registered by the next call of add_to_poll
+struct TcpQuery {
+    stream: TcpStream,
+    key: (String, RecordType),
+    server: SocketAddr,
+    outbuf: Vec<u8>,
+    written: usize,
+    inbuf: Vec<u8>,
+    registered: bool
+}
+
+pub struct CachingResolver<T> {
+    resolving: HashMap<(String, RecordType), Resolution>,
+    waiting: HashMap<String, LinkedList<T>>, // Tokens waiting for both the A and the AAAA query of a name
+    lookup_waiting: HashMap<(String, RecordType), LinkedList<T>>, // Tokens waiting for a lookup
+    retransmissions: ExpiryHandler<(String, RecordType, usize)>,
+    timeout: Duration, // Time until the first retransmission, doubled after every round over all servers
+    attempts: usize, // Number of rounds over all servers
+    ndots: usize, // Names with fewer dots are tried with the search list first
+    search: Vec<String>,
+    questions: HashMap<(String, RecordType), String>, // map name in a question to the name being resolved
+    encoding: HashMap<String, (String, usize)>, // 0x20 encoding of names with the number of outstanding queries
     socket4: Option<UdpSocket>,
     socket6: Option<UdpSocket>,
+    tcp: HashMap<usize, TcpQuery>, // TCP connections by their index after the UDP tokens
+    next_tcp_index: usize,
+    force_tcp: bool,
+    edns_payload: Option<u16>, // UDP payload size announced through EDNS, plain queries if none
     servers: Vec<SocketAddr>,
     cache4: Option<Cache<String, IpAddr>>,
     cache6: Option<Cache<String, IpAddr>>,
+    lookups: Cache<(String, RecordType), Answer>,
+    canonical_names: HashMap<String, (String, Instant)>, // CNAME chain targets with their expiry by the name they start at
+    hosts: HashMap<String, Addresses>, // Static addresses which take precedence over the cache and the network
     resolve_parallel: bool,
     ip_config: IpConfig,
     bufvec: Vec<u8>,
@@ -97,17 +249,31 @@ pub struct CachingResolver<'a, T> {
     inbuf: [u8; 0xFFFF]
 }
 
-impl<'a, T: Copy> CachingResolver<'a, T> {
-    pub fn new(ip_config: IpConfig, capacity: usize, cache_capacity: usize,
-               expiry_bucket_count: usize, expiry_bucket_secs: usize) -> Self {
+impl<T: Copy> CachingResolver<T> {
+    pub fn new(ip_config: IpConfig, capacity: usize, cache_capacity: usize) -> Self {
         let mut result = Self {
             resolving: HashMap::with_capacity(capacity),
+            waiting: HashMap::with_capacity(capacity),
+            lookup_waiting: HashMap::new(),
+            retransmissions: ExpiryHandler::new(Duration::from_millis(RETRANSMIT_RESOLUTION_MS)),
+            timeout: Duration::from_secs(5),
+            attempts: 2,
+            ndots: 1,
+            search: Vec::new(),
+            questions: HashMap::with_capacity(capacity),
             encoding: HashMap::with_capacity(capacity),
             servers: Vec::new(),
             socket4: None,
             socket6: None,
+            tcp: HashMap::new(),
+            next_tcp_index: 0,
+            force_tcp: false,
+            edns_payload: Some(EDNS_PAYLOAD_DEFAULT),
             cache4: None,
             cache6: None,
+            lookups: Cache::new(cache_capacity),
+            canonical_names: HashMap::new(),
+            hosts: HashMap::new(),
             resolve_parallel: true,
             ip_config: ip_config,
             bufvec: Vec::with_capacity(0xFFFF),
@@ -117,21 +283,22 @@ impl<'a, T: Copy> CachingResolver<'a, T> {
             inbuf: [0; 0xFFFF]
         };
 
+        // Nameservers are reached over the socket of their own family, independently of the supported versions
+        let ip = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
+        result.socket4 = UdpSocket::bind(&SocketAddr::new(ip, 0)).ok();
+        let ip = IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0));
+        result.socket6 = UdpSocket::bind(&SocketAddr::new(ip, 0)).ok();
         if !(result.ip_config.supported_versions & IP_V4).is_empty() {
-            let ip = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
-            result.socket4 = Some(UdpSocket::bind(&SocketAddr::new(ip, 0)).unwrap());
-            result.cache4 = Some(Cache::new(cache_capacity, expiry_bucket_count, expiry_bucket_secs));
+            result.cache4 = Some(Cache::new(cache_capacity));
         }
         if !(result.ip_config.supported_versions & IP_V6).is_empty() {
-            let ip = IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0));
-            result.socket6 = Some(UdpSocket::bind(&SocketAddr::new(ip, 0)).unwrap());
-            result.cache6 = Some(Cache::new(cache_capacity, expiry_bucket_count, expiry_bucket_secs));
+            result.cache6 = Some(Cache::new(cache_capacity));
         }
 
         result
     }
 
-    pub fn construct_query(qname: &String, qtype: RecordType, id: u16) -> Message {
+    pub fn construct_query(qname: &String, qtype: RecordType, id: u16, edns_payload: Option<u16>) -> Message {
         let mut msg = Message::new();
         let mut query = Query::new();
         let mut labels : Vec<String> = qname.split(".").map(|v : &str| String::from(v)).collect();
@@ -143,110 +310,539 @@ impl<'a, T: Copy> CachingResolver<'a, T> {
         msg.set_recursion_desired(true);
         msg.set_message_type(MessageType::Query);
         msg.add_query(query);
+        if let Some(payload) = edns_payload {
+            let mut edns = Edns::new();
+            edns.set_max_payload(payload);
+            msg.set_edns(edns);
+        }
         msg
     }
 
-    fn get_caches(&mut self) -> (Option<&mut Cache<String, IpAddr>>, Option<&mut Cache<String, IpAddr>>) {
-        let secondary_cache;
-        let preferred_cache = if self.ip_config.default_version == IP_V4 {
-            secondary_cache = self.cache6.as_mut();
-            self.cache4.as_mut()
+    fn send_query(mut bufvec: &mut Vec<u8>, qname: &String, qtype: RecordType, sock: &mut UdpSocket, to: &SocketAddr, id: u16, edns_payload: Option<u16>) {
+        bufvec.clear();
+        let msg = CachingResolver::<T>::construct_query(&qname, qtype, id, edns_payload);
+        sock.send_to(msg.to_vec().unwrap().as_slice(), to);
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn set_attempts(&mut self, attempts: usize) {
+        self.attempts = max(attempts, 1);
+    }
+
+    pub fn set_round_robin(&mut self, round_robin: RoundRobin) {
+        self.round_robin = round_robin;
+    }
+
+    pub fn set_edns_payload(&mut self, edns_payload: Option<u16>) {
+        self.edns_payload = edns_payload;
+    }
+
+    pub fn set_force_tcp(&mut self, force_tcp: bool) {
+        self.force_tcp = force_tcp;
+    }
+
+    pub fn set_servers(&mut self, servers: Vec<SocketAddr>) {
+        self.servers = servers;
+    }
+
+    // Names to try for a name according to the search list. Absolute names are only tried as they are.
+    fn search_names(&self, name: &String) -> Vec<String> {
+        if name.ends_with(".") {
+            return vec![name.clone()];
+        }
+        let absolute = name.clone() + ".";
+        let mut names: Vec<String> = self.search.iter().map(|domain| name.clone() + "." + domain + ".").collect();
+        if name.matches('.').count() >= self.ndots {
+            names.insert(0, absolute);
         } else {
-            secondary_cache = self.cache4.as_mut();
-            self.cache6.as_mut()
+            names.push(absolute);
+        }
+        names
+    }
+
+    // Index of the server which is asked first, the following attempts fail over to the next servers
+    fn first_server(&mut self) -> usize {
+        match self.round_robin {
+            RoundRobin::None => 0,
+            RoundRobin::Rotate => {
+                self.round_robin_index = self.round_robin_index.wrapping_add(1);
+                self.round_robin_index
+            },
+            RoundRobin::Random => rand::random::<usize>()
+        }
+    }
+
+    fn server(&self, first_server: usize, attempt: usize) -> Option<SocketAddr> {
+        if self.servers.is_empty() {
+            return None;
+        }
+        Some(self.servers[(first_server.wrapping_add(attempt)) % self.servers.len()])
+    }
+
+    // Randomize the case of the letters of a name (DNS 0x20 encoding), servers return the question unchanged
+    fn encode_0x20(qname: &String) -> String {
+        qname.chars().map(|c| {
+            if c.is_ascii_alphabetic() && rand::random::<bool>() {
+                c.to_ascii_uppercase()
+            } else {
+                c
+            }
+        }).collect()
+    }
+
+    // Send a query to the server responsible for the current attempt and schedule its retransmission
+    fn transmit(&mut self, key: &(String, RecordType)) {
+        let (name, first_server, attempt, serial, tcp, edns_payload) = {
+            let resolution = self.resolving.get_mut(key).unwrap();
+            resolution.serial += 1;
+            (resolution.names[resolution.name_index].clone(), resolution.first_server, resolution.attempt,
+             resolution.serial, resolution.tcp || self.force_tcp, if resolution.edns { self.edns_payload } else { None })
         };
-        (preferred_cache, secondary_cache)
+        if let Some(server) = self.server(first_server, attempt) {
+            let id = rand::random::<u16>();
+            let encoded = self.encoding[&name].0.clone();
+            let sock = if server.is_ipv4() { self.socket4.as_mut() } else { self.socket6.as_mut() };
+            let sent = if tcp {
+                self.connect_tcp(key, &encoded, server, id, edns_payload)
+            } else if let Some(sock) = sock {
+                CachingResolver::<T>::send_query(&mut self.bufvec, &encoded, key.1, sock, &server, id, edns_payload);
+                true
+            } else {
+                false
+            };
+            if sent {
+                self.resolving.get_mut(key).unwrap().transmissions.push((id, server));
+            }
+        }
+        let rounds = attempt / max(self.servers.len(), 1);
+        let delay = self.timeout * (1u32 << min(rounds, MAX_BACKOFF_DOUBLINGS));
+        self.retransmissions.add((key.0.clone(), key.1, serial), delay);
     }
 
-    fn send_query(mut bufvec: &mut Vec<u8>, qname: &String, qtype: RecordType, sock: &mut UdpSocket, to: &SocketAddr, id: u16) {
-        bufvec.clear();
-        let msg = CachingResolver::<T>::construct_query(&qname, qtype, id);
-        sock.send_to(msg.to_vec().unwrap().as_slice(), to);
+    // Open a TCP connection for a query, replacing the connection of a previous attempt
+    fn connect_tcp(&mut self, key: &(String, RecordType), qname: &String, server: SocketAddr, id: u16, edns_payload: Option<u16>) -> bool {
+        let stream = match TcpStream::connect(&server) {
+            Ok(s) => s,
+            Err(_) => return false
+        };
+        let msg = CachingResolver::<T>::construct_query(qname, key.1, id, edns_payload).to_vec().unwrap();
+        let mut outbuf = Vec::with_capacity(msg.len() + 2);
+        outbuf.push((msg.len() >> 8) as u8);
+        outbuf.push(msg.len() as u8);
+        outbuf.extend_from_slice(&msg);
+        self.tcp.retain(|_, c| c.key != *key);
+        let index = self.next_tcp_index;
+        self.next_tcp_index = self.next_tcp_index.wrapping_add(1);
+        self.tcp.insert(index, TcpQuery {
+            stream: stream,
+            key: key.clone(),
+            server: server,
+            outbuf: outbuf,
+            written: 0,
+            inbuf: Vec::new(),
+            registered: false
+        });
+        true
     }
 
-    pub fn query(&mut self, mut qname: String, token: T, expect_response: bool) -> ResolvePromise<T> {
-        if !qname.ends_with(".") {
-            qname.push('.');
+    fn acquire_name(&mut self, name: &String, qtype: RecordType, original: &String) {
+        self.questions.insert((name.clone(), qtype), original.clone());
+        self.encoding.entry(name.clone()).or_insert_with(|| (CachingResolver::<T>::encode_0x20(name), 0)).1 += 1;
+    }
+
+    fn release_name(&mut self, name: &String, qtype: RecordType) {
+        if self.questions.remove(&(name.clone(), qtype)).is_none() {
+            return;
         }
-        let cache_query = self.query_from_cache(&qname, RecordType::A, token, expect_response);
-        let mut in_cache = true;
-        match cache_query {
-            ResolvePromise::Resolving => {
-                in_cache = false;
+        let unused = match self.encoding.get_mut(name) {
+            Some(&mut (_, ref mut count)) => {
+                *count -= 1;
+                *count == 0
             },
-            _ => {}
+            None => false
+        };
+        if unused {
+            self.encoding.remove(name);
+        }
+    }
+
+    // Start resolving the name for which a resolution has been registered
+    fn start(&mut self, key: &(String, RecordType), names: Vec<String>, first_server: usize) {
+        self.acquire_name(&names[0], key.1, &key.0);
+        {
+            let resolution = self.resolving.get_mut(key).unwrap();
+            resolution.names = names;
+            resolution.first_server = first_server;
+        }
+        self.transmit(key);
+    }
+
+    // Continue with the next name of the search list. Returns false if there is none.
+    fn advance(&mut self, key: &(String, RecordType)) -> bool {
+        let (name, next_name) = {
+            let resolution = self.resolving.get_mut(key).unwrap();
+            if resolution.name_index + 1 >= resolution.names.len() {
+                return false;
+            }
+            resolution.name_index += 1;
+            resolution.attempt = 0;
+            resolution.transmissions.clear();
+            (resolution.names[resolution.name_index - 1].clone(), resolution.names[resolution.name_index].clone())
+        };
+        self.release_name(&name, key.1);
+        self.acquire_name(&next_name, key.1, &key.0);
+        self.transmit(key);
+        true
+    }
+
+    // Stop waiting for the response to a query
+    fn finish(&mut self, key: &(String, RecordType)) -> Option<Resolution> {
+        let resolution = self.resolving.remove(key);
+        if let Some(ref resolution) = resolution {
+            if !resolution.names.is_empty() {
+                self.release_name(&resolution.names[resolution.name_index], key.1);
+            }
+            if resolution.tcp || self.force_tcp {
+                self.tcp.retain(|_, c| c.key != *key);
+            }
+        }
+        resolution
+    }
+
+    // Addresses of a name from the static hosts or the cache, otherwise the token is passed to the function given to receive
+    // or expire once the queries for all supported versions have been answered or given up
+    pub fn query(&mut self, qname: String, token: T) -> ResolvePromise<T> {
+        let qname = qname.to_lowercase();
+        let names = self.search_names(&qname);
+        let mut original = qname;
+        if !original.ends_with(".") {
+            original.push('.');
+        }
+        if let Some(addresses) = self.hosts.get(&original) {
+            let mut addresses = addresses.clone();
+            if self.cache4.is_none() {
+                addresses.ipv4.clear();
+            }
+            if self.cache6.is_none() {
+                addresses.ipv6.clear();
+            }
+            if !addresses.is_empty() {
+                return ResolvePromise::Resolved(token, addresses);
+            }
+        }
+        if let Some(waiting) = self.waiting.get_mut(&original) {
+            waiting.push_back(token);
+            return ResolvePromise::Resolving;
+        }
+
+        let mut addresses = Addresses::default();
+        let mut missing = Vec::new();
+        if !CachingResolver::<T>::query_from_cache(self.cache4.as_mut(), &original, &mut addresses.ipv4) {
+            missing.push(RecordType::A);
+        }
+        if !CachingResolver::<T>::query_from_cache(self.cache6.as_mut(), &original, &mut addresses.ipv6) {
+            missing.push(RecordType::AAAA);
+        }
+        if missing.is_empty() {
+            return ResolvePromise::Resolved(token, addresses);
+        }
+
+        let mut waiting = LinkedList::new();
+        waiting.push_back(token);
+        self.waiting.insert(original.clone(), waiting);
+        let first_server = self.first_server();
+        for qtype in missing {
+            let key = (original.clone(), qtype);
+            if !self.resolving.contains_key(&key) {
+                // Otherwise the query has already been started by a lookup
+                self.resolving.insert(key.clone(), Resolution::new());
+                self.start(&key, names.clone(), first_server);
+            }
+        }
+        ResolvePromise::Resolving
+    }
+
+    // Look up the records of any type for a name. If the answer is not cached, the token is passed to the function
+    // given to receive or expire along with the answer once it is known. Names ending with a dot bypass the search list.
+    pub fn lookup(&mut self, qname: String, qtype: RecordType, token: T) -> LookupPromise<T> {
+        let qname = match IpAddr::from_str(&qname) {
+            // PTR lookups may be given the address itself
+            Ok(ip) if qtype == RecordType::PTR => reverse_name(&ip),
+            _ => qname.to_lowercase()
+        };
+        let names = self.search_names(&qname);
+        let mut original = qname;
+        if !original.ends_with(".") {
+            original.push('.');
+        }
+        let key = (original, qtype);
+        if let Some(waiting) = self.lookup_waiting.get_mut(&key) {
+            waiting.push_back(token);
+            return LookupPromise::Pending;
+        }
+        if let Some(Some(mut answers)) = self.lookups.query(&key, false) {
+            return LookupPromise::Answered(token, answers.remove(0));
+        }
+
+        let mut waiting = LinkedList::new();
+        waiting.push_back(token);
+        self.lookup_waiting.insert(key.clone(), waiting);
+        if !self.resolving.contains_key(&key) {
+            // Otherwise the lookup shares the query for the addresses of the name
+            self.resolving.insert(key.clone(), Resolution::new());
+            let first_server = self.first_server();
+            self.start(&key, names, first_server);
+        }
+        LookupPromise::Pending
+    }
+
+    fn address_cache(&mut self, qtype: RecordType) -> Option<&mut Cache<String, IpAddr>> {
+        match qtype {
+            RecordType::A => self.cache4.as_mut(),
+            RecordType::AAAA => self.cache6.as_mut(),
+            _ => None
+        }
+    }
+
+    // Pass the tokens waiting for the lookup of a finished query to the function. Those waiting for the addresses
+    // of its name follow once neither the A nor the AAAA query is outstanding.
+    fn complete(&mut self, key: &(String, RecordType), answer: &Answer, fun: &mut FnMut (T, Completion)) {
+        if let Some(waiting) = self.lookup_waiting.remove(key) {
+            for tk in waiting {
+                fun(tk, Completion::Answered(answer.clone()));
+            }
+        }
+        let name = &key.0;
+        if self.resolving.contains_key(&(name.clone(), RecordType::A))
+            || self.resolving.contains_key(&(name.clone(), RecordType::AAAA)) {
+            return;
         }
-        if !in_cache && !(IP_V4 & self.ip_config.supported_versions).is_empty() {
-            CachingResolver::<T>::send_query(&mut self.bufvec, &qname, RecordType::A, self.socket4.as_mut().unwrap(), &self.servers[0], 10);
+        if let Some(waiting) = self.waiting.remove(name) {
+            let mut addresses = Addresses::default();
+            if let Some(Some(ips)) = self.cache4.as_ref().and_then(|c| c.peek(name)) {
+                addresses.ipv4 = ips;
+            }
+            if let Some(Some(ips)) = self.cache6.as_ref().and_then(|c| c.peek(name)) {
+                addresses.ipv6 = ips;
+            }
+            for tk in waiting {
+                fun(tk, Completion::Resolved(addresses.clone()));
+            }
         }
-        if !in_cache && !(IP_V6 & self.ip_config.supported_versions).is_empty() {
-            CachingResolver::<T>::send_query(&mut self.bufvec, &qname, RecordType::AAAA, self.socket4.as_mut().unwrap(), &self.servers[0], 10);
+    }
+
+    // Time until the next retransmission is due
+    pub fn next_expiry(&self) -> Option<Duration> {
+        self.retransmissions.next_expiry()
+    }
+
+    // Retransmit queries which have not been answered in time. Queries which ran out of attempts
+    // fail and their waiting tokens are passed to the function.
+    pub fn expire(&mut self, fun: &mut FnMut (T, Completion)) {
+        let mut expired: Vec<(String, RecordType, usize)> = Vec::new();
+        self.retransmissions.clean(&mut |e: &(String, RecordType, usize)| {
+            expired.push(e.clone());
+        });
+        for (qname, qtype, serial) in expired {
+            let key = (qname, qtype);
+            match self.resolving.get(&key) {
+                Some(resolution) if resolution.serial == serial => {},
+                _ => continue // Answered or retransmitted in the meantime
+            }
+            self.fail_over(&key, fun);
         }
-        cache_query
     }
 
+    // Ask the next server after a timeout or a server failure. Once all attempts are used up, continue with
+    // the next name of the search list or give up.
+    fn fail_over(&mut self, key: &(String, RecordType), fun: &mut FnMut (T, Completion)) {
+        let max_attempts = self.attempts * max(self.servers.len(), 1);
+        let attempt = {
+            let resolution = self.resolving.get_mut(key).unwrap();
+            resolution.attempt += 1;
+            resolution.attempt
+        };
+        if attempt < max_attempts {
+            self.transmit(key);
+            return;
+        }
+        if self.advance(key) {
+            return;
+        }
+        self.finish(key);
+        // The failure says nothing about the name, so it is only kept until the waiting tokens have seen it
+        let ttl = Duration::from_secs(CACHE_MINIMUM_TTL_SECS);
+        if let Some(cache) = self.address_cache(key.1) {
+            cache.insert_negative(key.0.clone(), ttl);
+        }
+        self.lookups.remove(key);
+        self.lookups.insert(key.clone(), Answer::Failure, ttl);
+        self.complete(key, &Answer::Failure, fun);
+    }
+
+    // Register the sockets on the first call and TCP connections opened since the previous call.
+    // The IPv4 socket always uses the start token and the IPv6 socket the one after it.
     pub fn add_to_poll(&mut self, poll: &mut Poll, start_token: usize) -> usize {
         let mut added = 0;
-        self.epoll_start_token = Some(start_token);
-        if self.socket4.is_some() {
-            poll.register(self.socket4.as_mut().unwrap(), Token(start_token + added), Ready::readable(), PollOpt::edge());
-            added += 1;
+        if self.epoll_start_token.is_none() {
+            self.epoll_start_token = Some(start_token);
+            if let Some(ref socket4) = self.socket4 {
+                poll.register(socket4, Token(start_token), Ready::readable(), PollOpt::edge());
+                added += 1;
+            }
+            if let Some(ref socket6) = self.socket6 {
+                poll.register(socket6, Token(start_token + 1), Ready::readable(), PollOpt::edge());
+                added += 1;
+            }
         }
-        if self.socket6.is_some() {
-            poll.register(self.socket6.as_mut().unwrap(), Token(start_token + added), Ready::readable(), PollOpt::edge());
-            added += 1;
+        for (index, connection) in self.tcp.iter_mut() {
+            if !connection.registered {
+                let token = Token(start_token + UDP_TOKEN_COUNT + index);
+                poll.register(&connection.stream, token, Ready::readable() | Ready::writable(), PollOpt::edge())
+                    .expect("Failed to register poll.");
+                connection.registered = true;
+                added += 1;
+            }
         }
         added
     }
 
-    pub fn receive(&mut self, token: usize, fun: &mut FnMut (T)) -> bool {
-        let mut recv;
-        if Some(token) == self.epoll_start_token {
-            recv = self.socket4.as_mut().unwrap().recv_from(&mut self.inbuf);
-            if recv.is_err() {
-                return false;
+    pub fn receive(&mut self, token: usize, fun: &mut FnMut (T, Completion)) -> bool {
+        let start_token = match self.epoll_start_token {
+            Some(t) => t,
+            None => return false
+        };
+        if token >= start_token + UDP_TOKEN_COUNT {
+            self.receive_tcp(token - start_token - UDP_TOKEN_COUNT, fun);
+            return false;
+        }
+        let sock = if token == start_token { self.socket4.as_mut() } else { self.socket6.as_mut() };
+        let recv = match sock {
+            Some(sock) => sock.recv_from(&mut self.inbuf),
+            None => return false
+        };
+        let (length, source) = match recv {
+            Ok(r) => r,
+            Err(_) => return false
+        };
+        let msg = Message::from_vec(&self.inbuf[0..length]);
+        if let Ok(msg) = msg {
+            let rcode = CachingResolver::<T>::response_code(&self.inbuf[0..length], &msg);
+            self.process(msg, rcode, source, false, fun);
+        }
+        true
+    }
+
+    // Continue the query of a TCP connection. The connection is closed once the response is complete.
+    fn receive_tcp(&mut self, index: usize, fun: &mut FnMut (T, Completion)) {
+        let mut done = false;
+        let mut response = None;
+        {
+            let connection = match self.tcp.get_mut(&index) {
+                Some(c) => c,
+                None => return
+            };
+            while !done && connection.written < connection.outbuf.len() {
+                match connection.stream.write(&connection.outbuf[connection.written..]) {
+                    Ok(0) => done = true,
+                    Ok(n) => connection.written += n,
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(_) => done = true
+                }
             }
-        } else if Some(token - 1) == self.epoll_start_token {
-            recv = self.socket6.as_mut().unwrap().recv_from(&mut self.inbuf);
-            if recv.is_err() {
-                return false;
+            while !done && connection.written == connection.outbuf.len() {
+                match connection.stream.read(&mut self.inbuf) {
+                    Ok(0) => done = true,
+                    Ok(n) => connection.inbuf.extend_from_slice(&self.inbuf[0..n]),
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(_) => done = true
+                }
+            }
+            // Messages are prefixed with their length
+            if connection.inbuf.len() >= 2 {
+                let length = ((connection.inbuf[0] as usize) << 8) | connection.inbuf[1] as usize;
+                if connection.inbuf.len() >= 2 + length {
+                    let data = &connection.inbuf[2..2 + length];
+                    response = Message::from_vec(data).ok().map(|msg| {
+                        let rcode = CachingResolver::<T>::response_code(data, &msg);
+                        (msg, rcode, connection.server)
+                    });
+                    done = true;
+                }
             }
-        } else {
-            return true;
         }
-        if recv.is_err() {
-            return true;
+        if done {
+            self.tcp.remove(&index);
         }
-        let recv = recv.unwrap();
-        let msg = Message::from_vec(&self.inbuf[0..recv.0]);
-        if msg.is_err() {
-            return true;
+        if let Some((msg, rcode, source)) = response {
+            self.process(msg, rcode, source, true, fun);
         }
-        let msg = msg.unwrap();
+    }
+
+    // Extended response code of a message. The upper eight bits are carried by the OPT record (RFC 6891).
+    // Taken from the raw header because trust-dns panics on codes it does not know.
+    fn response_code(data: &[u8], msg: &Message) -> u16 {
+        let high = msg.edns().map_or(0, |e| e.rcode_high()) as u16;
+        (high << 4) | (data[3] & 0x0F) as u16
+    }
+
+    fn process(&mut self, msg: Message, rcode: u16, source: SocketAddr, tcp: bool, fun: &mut FnMut (T, Completion)) {
         let queries = msg.queries();
-        if queries.len() != 1 {
-            return true;
+        if queries.len() != 1 || msg.message_type() != MessageType::Response {
+            return;
         }
         let qname = queries[0].name();
         let qname_str = qname.to_string();
         let qtype = queries[0].query_type();
-        if qtype != RecordType::A && qtype != RecordType::AAAA {
-            return true;
-        }
-
-        let mut res = self.resolving.remove(&(qname_str, qtype));
 
+        // Only accept responses to outstanding queries from the server they were sent to, with the same ID and case
+        let key = match self.questions.get(&(qname_str.to_lowercase(), qtype)) {
+            Some(original) => (original.clone(), qtype),
+            None => return
+        };
+        let valid = match (self.resolving.get(&key), self.encoding.get(&qname_str.to_lowercase())) {
+            (Some(resolution), Some(&(ref encoded, _))) => {
+                *encoded == qname_str && resolution.transmissions.contains(&(msg.id(), source))
+            },
+            _ => false
+        };
+        if !valid {
+            return;
+        }
+        let edns_rejected = rcode == RCODE_BADVERS || (msg.edns().is_none() && (rcode == RCODE_FORMERR || rcode == RCODE_NOTIMP));
+        if edns_rejected && self.edns_payload.is_some() && self.resolving[&key].edns {
+            // Ask the same server again without OPT record
+            self.resolving.get_mut(&key).unwrap().edns = false;
+            self.transmit(&key);
+            return;
+        }
+        if msg.truncated() && !tcp {
+            // Ask the same server again over TCP for the complete response
+            self.resolving.get_mut(&key).unwrap().tcp = true;
+            self.transmit(&key);
+            return;
+        }
+        if rcode != RCODE_NOERROR && rcode != RCODE_NXDOMAIN {
+            // SERVFAIL, REFUSED and the like only concern the server, so the next one is asked as after a timeout
+            self.fail_over(&key, fun);
+            return;
+        }
 
         // Flatten CNAME records which we are interested in (name from the question section)
         let mut flatten = None;
         let mut i: usize = 0;
         let mut tname = qname;
+        let mut cname_ttl = None;
         'outer:while i < CNAME_CHAIN_LIMIT {
             for answer in msg.answers() {
                 if answer.name() == tname {
                     match answer.rdata() {
                         &RData::CNAME(ref dst) => {
                             tname = dst;
+                            cname_ttl = Some(min(cname_ttl.unwrap_or(answer.ttl()), answer.ttl()));
                         }
                         _ => {
                             flatten = Some(tname);
@@ -257,201 +853,422 @@ impl<'a, T: Copy> CachingResolver<'a, T> {
             }
             i += 1;
         }
+        let canonical = tname.to_string().to_lowercase();
+        if canonical != key.0 {
+            // The chain is valid as long as its shortest-lived CNAME record
+            let ttl = max(Duration::from_secs(cname_ttl.unwrap_or(0) as u64), Duration::from_secs(CACHE_MINIMUM_TTL_SECS));
+            let target = String::from(canonical.trim_end_matches('.'));
+            self.canonical_names.insert(key.0.clone(), (target, Instant::now() + ttl));
+        }
 
+        let mut answered = false;
+        let mut records = Vec::new();
+        let mut ttl = None;
         for answer in msg.answers() {
             if answer.rr_type() != qtype {
                 continue;
             }
+            answered = true;
+            records.extend(Record::from_rdata(answer.rdata()));
+            ttl = Some(min(ttl.unwrap_or(answer.ttl()), answer.ttl()));
 
-            // If applicable, use the flattened name
+            // If applicable, use the flattened name, which is the name being resolved
             let name = match flatten {
-                None => answer.name(),
-                Some(n) => if answer.name() == n {
-                    qname
-                } else {
-                    answer.name()
-                }
+                Some(n) if answer.name() == n => key.0.clone(),
+                _ => answer.name().to_string().to_lowercase()
             };
 
-
             if let (Some(ref mut cache4), &RData::A(ip4)) = (self.cache4.as_mut(), answer.rdata()) {
-                cache4.insert(name.to_string(), IpAddr::V4(ip4), Duration::from_secs(answer.ttl() as u64));
+                cache4.insert(name, IpAddr::V4(ip4), Duration::from_secs(answer.ttl() as u64));
             } else if let (Some(ref mut cache6), &RData::AAAA(ip6)) = (self.cache6.as_mut(), answer.rdata()) {
-                cache6.insert(name.to_string(), IpAddr::V6(ip6), Duration::from_secs(answer.ttl() as u64));
+                cache6.insert(name, IpAddr::V6(ip6), Duration::from_secs(answer.ttl() as u64));
             }
         }
 
-        match res {
-            None => {
-                return true;
-            },
-            Some((ref mut list, _)) => {
-                'l: loop {
-                    match list.pop_front() {
-                        None => {
-                            break 'l;
-                        },
-                        Some(tk) => {
-                            fun(tk);
-                        }
-                    }
-                }
+        if !answered && self.advance(&key) {
+            // Wait for the response for the next name of the search list
+            return;
+        }
+        self.finish(&key);
+        let (answer, ttl) = if answered {
+            (Answer::Records(records), Duration::from_secs(ttl.unwrap() as u64))
+        } else {
+            // NXDOMAIN or NODATA, remember it so that waiting queries are answered negatively
+            let ttl = CachingResolver::<T>::negative_ttl(&msg);
+            if let Some(cache) = self.address_cache(qtype) {
+                cache.insert_negative(key.0.clone(), ttl);
+            }
+            let answer = if rcode == RCODE_NXDOMAIN {
+                Answer::NonExistent
+            } else {
+                Answer::Records(Vec::new())
+            };
+            (answer, ttl)
+        };
+        self.lookups.remove(&key);
+        self.lookups.insert(key.clone(), answer.clone(), ttl);
+        self.complete(&key, &answer, fun);
+    }
+
+    // Negative caching time according to RFC 2308, i.e. the minimum of the SOA TTL and the SOA minimum field
+    fn negative_ttl(msg: &Message) -> Duration {
+        for record in msg.name_servers() {
+            if let &RData::SOA(ref soa) = record.rdata() {
+                return Duration::from_secs(min(record.ttl(), soa.minimum()) as u64);
             }
         }
-        true
+        Duration::from_secs(NEGATIVE_TTL_FALLBACK)
     }
 
-    /*pub fn resolved((qname, qtype): (String, RecordType), fun: &'a mut FnMut()) -> Option<String, RecordType> {
+    // Target of the CNAME chain starting at the name, if it was part of a response
+    pub fn canonical_name(&self, name: &str) -> Option<String> {
+        let mut qname = name.to_lowercase();
+        if !qname.ends_with(".") {
+            qname.push('.');
+        }
+        match self.canonical_names.get(&qname) {
+            Some(&(ref canonical, expiry)) if expiry > Instant::now() => Some(canonical.clone()),
+            _ => None
+        }
+    }
 
-    }*/
+    // Add the cached addresses of a name to the list. Returns false if the cache of a supported version has no entry.
+    fn query_from_cache(cache: Option<&mut Cache<String, IpAddr>>, qname: &String, list: &mut Vec<IpAddr>) -> bool {
+        let cache = match cache {
+            Some(c) => c,
+            None => return true // Version not supported
+        };
+        match cache.query(qname, true) {
+            Some(Some(ips)) => {
+                list.extend(ips);
+                true
+            },
+            Some(None) => true, // Negative cache entry (NXDOMAIN or NODATA)
+            None => false
+        }
+    }
 
-    fn query_from_cache(&mut self, qname: &String, qtype: RecordType, token: T, expect_response: bool) -> ResolvePromise<T> {
-        /*let entry = self.resolving.entry(qname.clone());
-        if let Entry::Vacant(_) = entry {
-            return ResolvePromise::Resolving;
+    // Number of cache hits and misses of address queries
+    pub fn cache_statistics(&self) -> (u64, u64) {
+        let mut statistics = (0, 0);
+        for cache in self.cache4.iter().chain(self.cache6.iter()) {
+            statistics.0 += cache.hits();
+            statistics.1 += cache.misses();
         }
-        entry.or_insert(LinkedList::new()).push_back(token);*/
-        let mut was_none = false;
-        {
-            let mut res = self.resolving.get_mut(&(qname.clone(), qtype));
-            //let mut res = res.as_mut();
-            match res {
-                Some(&mut (ref mut list, _)) => {
-                    if !expect_response {
-                        list.push_back(token);
-                        return ResolvePromise::Resolving;
+        statistics
+    }
+
+    // Write the cache to a file, one record per line: type, name, expiry as UNIX time and the address,
+    // which is missing for negative entries. CNAME lines map a name to the target of its chain, followed by the expiry.
+    pub fn save_cache(&self, path: &str) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        // Expiry times are kept on the monotonic clock and only converted for the file
+        let now = Instant::now();
+        let unix_now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        for &(qtype, cache) in [("A", &self.cache4), ("AAAA", &self.cache6)].iter() {
+            if let Some(ref cache) = *cache {
+                for (name, address, expiry) in cache.entries() {
+                    let secs = unix_now + time_until(expiry, now).as_secs();
+                    match address {
+                        Some(ip) => writeln!(file, "{} {} {} {}", qtype, name, secs, ip)?,
+                        None => writeln!(file, "{} {} {}", qtype, name, secs)?
                     }
-                },
-                None => {
-                    was_none = true;
                 }
             }
         }
+        for (name, &(ref canonical, expiry)) in self.canonical_names.iter() {
+            if expiry > now {
+                writeln!(file, "CNAME {} {} {}", name, canonical, unix_now + time_until(expiry, now).as_secs())?;
+            }
+        }
+        file.flush()
+    }
 
-        /*let preferred_cache = if self.ip_config.default_version == IP_V4 {
-            self.cache4.as_mut().unwrap()
-        } else {
-            self.cache4.as_mut().unwrap()
-        };
-        let secondary_cache = if self.ip_config.default_version == IP_V6 {
-            &self.cache6
-        } else {
-            &self.cache4
-        };*/
-        let mut nocache = false;
-        {
-            let (preferred_cache, _) = self.get_caches();
-            let mut preferred_cache = preferred_cache.unwrap();
-
-            //let mut preferred_cache = preferred_cache.unwrap();
-            let records = preferred_cache.query(qname, true);
-            match records {
-                Some(option_list) => {
-                    // in cache
-                    match option_list {
-                        &Some(ref ips) => {
-                            return ResolvePromise::Resolved(token, Some(ips[0]));
-                        },
-                        &None => {
-                            // negative cache entry (NXDOMAIN)
-                            return ResolvePromise::Resolved(token, None);
-                        }
-                    }
-                },
-                None => {
-                    // not in cache
-                    nocache = true;
+    // Restore a cache written by save_cache, skipping records which have expired in the meantime
+    pub fn load_cache(&mut self, path: &str) -> io::Result<()> {
+        let file = BufReader::new(File::open(path)?);
+        let unix_now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        for line in file.lines() {
+            let line = line?;
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.len() < 3 {
+                continue;
+            }
+            // CNAME lines carry the expiry after the target
+            let expiry_index = if words[0] == "CNAME" { 3 } else { 2 };
+            let ttl = match words.get(expiry_index).map(|secs| u64::from_str(secs)) {
+                Some(Ok(secs)) if secs > unix_now => Duration::from_secs(secs - unix_now),
+                _ => continue
+            };
+            if words[0] == "CNAME" {
+                self.canonical_names.insert(String::from(words[1]), (String::from(words[2]), Instant::now() + ttl));
+                continue;
+            }
+            let (cache, ipv4) = match words[0] {
+                "A" => (self.cache4.as_mut(), true),
+                "AAAA" => (self.cache6.as_mut(), false),
+                _ => continue
+            };
+            if let Some(cache) = cache {
+                let name = String::from(words[1]);
+                match words.get(3).map(|ip| IpAddr::from_str(ip)) {
+                    Some(Ok(ip)) if ip.is_ipv4() == ipv4 => cache.insert_with_direction(name, ip, ttl, true),
+                    Some(_) => {},
+                    None => cache.insert_negative(name, ttl)
                 }
             }
         }
-        if was_none && nocache {
-            let mut list = LinkedList::new();
-            list.push_back(token);
-            self.resolving.insert((qname.clone(), qtype), (list, 0));
-        }
-        return ResolvePromise::Resolving;
+        Ok(())
     }
 
-    pub fn from_config(ip_config: IpConfig, capacity: usize, cache_capacity: usize,
-                       expiry_bucket_count: usize, expiry_bucket_secs: usize) -> Self {
-        let mut result = CachingResolver::new(ip_config, capacity, cache_capacity,
-                                              expiry_bucket_count, expiry_bucket_secs);
+    /*pub fn resolved((qname, qtype): (String, RecordType), fun: &'a mut FnMut()) -> Option<String, RecordType> {
+
+    }*/
+
+    pub fn from_config(ip_config: IpConfig, capacity: usize, cache_capacity: usize) -> Self {
+        let mut result = CachingResolver::new(ip_config, capacity, cache_capacity);
         result.parse_dns_config();
+        result.parse_hosts();
         result
     }
 
+    // Pin a name to an address, e.g. to point it at a local server. Names can have several addresses.
+    pub fn add_host(&mut self, name: &str, ip: IpAddr) {
+        let mut name = name.to_lowercase();
+        if !name.ends_with(".") {
+            name.push('.');
+        }
+        let addresses = self.hosts.entry(name).or_insert_with(Addresses::default);
+        let list = match ip {
+            IpAddr::V4(_) => &mut addresses.ipv4,
+            IpAddr::V6(_) => &mut addresses.ipv6
+        };
+        if !list.contains(&ip) {
+            list.push(ip);
+        }
+    }
+
+    fn parse_hosts(&mut self) {
+        if let Ok(f) = File::open("/etc/hosts") {
+            self.read_hosts(BufReader::new(&f));
+        }
+    }
+
+    // Entries of the hosts file format, the canonical name followed by its aliases
+    fn read_hosts<R: BufRead>(&mut self, reader: R) {
+        for wrapped_line in reader.lines() {
+            let line = match wrapped_line {
+                Ok(line) => line,
+                Err(_) => break
+            };
+            let words: Vec<&str> = line.split('#').next().unwrap().split_whitespace().collect();
+            // Scoped IPv6 addresses such as fe80::1%eth0 cannot be parsed and are skipped
+            if let Some(ip) = words.first().and_then(|w| IpAddr::from_str(w).ok()) {
+                for name in words[1..].iter() {
+                    self.add_host(name, ip);
+                }
+            }
+        }
+    }
+
     fn parse_dns_config(&mut self) {
-        let f = File::open("/etc/resolv.conf");
-        if f.is_ok() {
-            let f = f.unwrap();
-            let file = BufReader::new(&f);
-            for wrapped_line in file.lines() {
-                let line = wrapped_line.unwrap();
-                let words: Vec<&str> = line.split_whitespace().collect();
-                if words.len() >= 2 && words[0] == "nameserver" {
-                    let ip = IpAddr::from_str(words[1]);
-                    if ip.is_ok() {
-                        self.servers.push(SocketAddr::new(ip.unwrap(), 53));
-                    }
+        if let Ok(f) = File::open("/etc/resolv.conf") {
+            self.read_dns_config(BufReader::new(&f));
+        }
+    }
+
+    // Nameservers, search list and options in the format of resolv.conf(5)
+    fn read_dns_config<R: BufRead>(&mut self, reader: R) {
+        for wrapped_line in reader.lines() {
+            let line = wrapped_line.unwrap();
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.len() >= 2 && words[0] == "nameserver" {
+                let ip = IpAddr::from_str(words[1]);
+                if ip.is_ok() {
+                    self.servers.push(SocketAddr::new(ip.unwrap(), 53));
+                }
+            } else if words.len() >= 2 && (words[0] == "search" || words[0] == "domain") {
+                // The last search or domain line wins
+                self.search = words[1..].iter().map(|d| d.trim_matches('.').to_lowercase()).collect();
+            } else if words.len() >= 2 && words[0] == "options" {
+                for option in words[1..].iter() {
+                    self.parse_dns_option(option);
                 }
             }
         }
     }
+
+    // Options with the limits of resolv.conf(5)
+    fn parse_dns_option(&mut self, option: &str) {
+        let mut parts = option.splitn(2, ':');
+        let name = parts.next().unwrap();
+        let value = parts.next().and_then(|v| usize::from_str(v).ok());
+        match (name, value) {
+            ("timeout", Some(secs)) => self.timeout = Duration::from_secs(min(max(secs, 1), 30) as u64),
+            ("attempts", Some(attempts)) => self.attempts = min(max(attempts, 1), 5),
+            ("ndots", Some(ndots)) => self.ndots = min(ndots, 15),
+            ("rotate", None) => self.round_robin = RoundRobin::Rotate,
+            _ => {}
+        }
+    }
 }
 
-pub struct Cache<TKey, TValue> {
-    data: HashMap<Rc<TKey>, Option<VecDeque<TValue>>>,
-    expiry: ExpiryHandler<ExpiryRef<TKey>>,
-    value_capacity: usize
+// Records of a key with their expiry times, a negative entry if there are none
+struct CacheEntry<TValue> {
+    records: Option<VecDeque<(TValue, Instant)>>,
+    negative_expiry: Instant,
+    last_used: u64,
+    timer: Option<ExpiryHandle> // Due at the earliest expiry
 }
 
+impl<TValue> CacheEntry<TValue> {
+    // Drop expired records. Returns whether the entry itself has expired.
+    fn purge(&mut self, now: Instant) -> bool {
+        match self.records {
+            Some(ref mut records) => {
+                records.retain(|&(_, expiry)| expiry > now);
+                records.is_empty()
+            },
+            None => self.negative_expiry <= now
+        }
+    }
+
+    fn expiry(&self) -> Instant {
+        match self.records {
+            Some(ref records) => records.iter().map(|&(_, expiry)| expiry).min().unwrap_or(self.negative_expiry),
+            None => self.negative_expiry
+        }
+    }
+}
+
+pub struct Cache<TKey, TValue> {
+    data: HashMap<Rc<TKey>, CacheEntry<TValue>>,
+    usage: BTreeMap<u64, Rc<TKey>>, // Keys by their last use, the least recently used one first
+    expiry: ExpiryHandler<Rc<TKey>>,
+    capacity: usize,
+    value_capacity: usize,
+    clock: u64, // Incremented on every use of an entry
+    hits: u64,
+    misses: u64
+}
 
-// TODO: Implement record expiry
-impl<TKey: Eq + Hash, TValue> Cache<TKey, TValue> {
-    pub fn new(capacity: usize, expiry_bucket_count: usize, expiry_bucket_secs: usize) -> Self {
+impl<TKey: Eq + Hash, TValue: Clone + PartialEq> Cache<TKey, TValue> {
+    pub fn new(capacity: usize) -> Self {
         Self {
             data: HashMap::with_capacity(capacity),
-            expiry: ExpiryHandler::new(expiry_bucket_count, expiry_bucket_secs),
-            value_capacity: 16
+            usage: BTreeMap::new(),
+            expiry: ExpiryHandler::new(Duration::from_secs(CACHE_TIMER_RESOLUTION_SECS)),
+            capacity: max(capacity, 1),
+            value_capacity: 16,
+            clock: 0,
+            hits: 0,
+            misses: 0
         }
     }
 
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    // Remove the records which have expired
     pub fn clean(&mut self) {
-        let ref mut dat = self.data;
-        let mut fun = |e: &ExpiryRef<TKey> | {
-            let mut cloned_ref = e.get_rc();
-            let mut remove = false;
-            {
-                let rec = dat.get_mut(Rc::get_mut(&mut cloned_ref).unwrap());
-                if rec.is_none() {
-                    return;
-                }
-                let rec = rec.unwrap().as_mut();
-                if rec.is_some() {
-                    if e.is_negative() {
-                        return;
-                    }
-                    let mut records = rec.unwrap();
-                    records.remove(e.get_index());
-                    if records.len() == 0 {
-                        remove = true;
-                    }
-                } else {
-                    if e.is_positive() {
-                        return;
-                    }
-                    remove = true;
-                }
+        let mut expired: Vec<Rc<TKey>> = Vec::new();
+        self.expiry.clean(&mut |key: &Rc<TKey>| {
+            expired.push(key.clone());
+        });
+        let now = Instant::now();
+        for key in expired {
+            let empty = match self.data.get_mut(&*key) {
+                Some(entry) => {
+                    entry.timer = None;
+                    entry.purge(now)
+                },
+                None => continue
+            };
+            if empty {
+                self.remove(&key);
+            } else {
+                self.schedule(&key, now);
             }
-            if remove {
-                dat.remove(Rc::get_mut(&mut cloned_ref).unwrap());
+        }
+    }
+
+    pub fn remove(&mut self, key: &TKey) {
+        if let Some(entry) = self.data.remove(key) {
+            self.usage.remove(&entry.last_used);
+            if let Some(handle) = entry.timer {
+                self.expiry.cancel(handle);
             }
+        }
+    }
+
+    // Arm the timer of an entry for its earliest expiry
+    fn schedule(&mut self, key: &Rc<TKey>, now: Instant) {
+        let (previous, expiry) = match self.data.get_mut(&**key) {
+            Some(entry) => (entry.timer.take(), entry.expiry()),
+            None => return
         };
-        self.expiry.clean(&mut fun);
+        if let Some(handle) = previous {
+            self.expiry.cancel(handle);
+        }
+        let handle = self.expiry.add(key.clone(), time_until(expiry, now));
+        self.data.get_mut(&**key).unwrap().timer = Some(handle);
+    }
+
+    // Mark the entry of a key as the most recently used one
+    fn touch(&mut self, key: &Rc<TKey>) {
+        self.clock += 1;
+        if let Some(entry) = self.data.get_mut(&**key) {
+            self.usage.remove(&entry.last_used);
+            entry.last_used = self.clock;
+        }
+        self.usage.insert(self.clock, key.clone());
     }
 
+    // Remove the least recently used entries until the capacity is no longer exceeded
+    fn evict(&mut self) {
+        while self.data.len() > self.capacity {
+            let oldest = match self.usage.iter().next() {
+                Some((_, key)) => key.clone(),
+                None => return
+            };
+            self.remove(&oldest);
+        }
+    }
+
+    // Positive records take precedence over a negative entry for the same key
     pub fn insert_negative(&mut self, key: TKey, ttl: Duration) {
-        self.expiry.add(ExpiryRef::Negative(Rc::new(key)), ttl);
+        let ttl = max(ttl, Duration::from_secs(CACHE_MINIMUM_TTL_SECS));
+        let now = Instant::now();
+        let keyref = Rc::new(key);
+        let inserted = {
+            let entry = self.data.entry(keyref.clone()).or_insert(CacheEntry {
+                records: None,
+                negative_expiry: now,
+                last_used: 0,
+                timer: None
+            });
+            if entry.records.is_none() || entry.purge(now) {
+                entry.records = None;
+                entry.negative_expiry = now + ttl;
+                true
+            } else {
+                false
+            }
+        };
+        if inserted {
+            self.schedule(&keyref, now);
+            self.touch(&keyref);
+            self.evict();
+        }
     }
 
     pub fn insert(&mut self, key: TKey, value: TValue, ttl: Duration) {
@@ -459,45 +1276,267 @@ impl<TKey: Eq + Hash, TValue> Cache<TKey, TValue> {
     }
 
     pub fn insert_with_direction(&mut self, key: TKey, value: TValue, ttl: Duration, back: bool) {
-        let expiry = SystemTime::now() + ttl;
+        let ttl = max(ttl, Duration::from_secs(CACHE_MINIMUM_TTL_SECS));
+        let now = Instant::now();
+        let expiry = now + ttl;
         let keyref = Rc::new(key);
-        let entry = self.data.entry(keyref.clone()).or_insert(Some(VecDeque::with_capacity(self.value_capacity)));
-        let mut index = 0;
-        match *entry {
-            None => {
-                let mut records = VecDeque::with_capacity(self.value_capacity);
-                if back {
-                    index = records.len();
-                    records.push_back(value);
-                } else {
-                    records.push_front(value);
-                }
-                *entry = Some(records);
-            },
-            Some(ref mut records) => {
-                if back {
-                    records.push_back(value);
-                    index = records.len();
-                } else {
-                    records.push_front(value);
-                }
+        {
+            let value_capacity = self.value_capacity;
+            let entry = self.data.entry(keyref.clone()).or_insert(CacheEntry {
+                records: None,
+                negative_expiry: expiry,
+                last_used: 0,
+                timer: None
+            });
+            if entry.records.is_none() {
+                entry.records = Some(VecDeque::with_capacity(value_capacity));
+            }
+            let records = entry.records.as_mut().unwrap();
+            // A record which is already known only gets its expiry renewed
+            match records.iter().position(|&(ref v, _)| *v == value) {
+                Some(index) => records[index].1 = expiry,
+                None if back => records.push_back((value, expiry)),
+                None => records.push_front((value, expiry))
             }
         }
-        self.expiry.add((ExpiryRef::Positive(keyref, index)), ttl);
+        self.schedule(&keyref, now);
+        self.touch(&keyref);
+        self.evict();
     }
 
-    pub fn query(&mut self, key: &TKey, rotate: bool) -> Option<&Option<VecDeque<TValue>>> {
+    // Records of a key, None if they are unknown and Some(None) for a negative entry
+    pub fn query(&mut self, key: &TKey, rotate: bool) -> Option<Option<Vec<TValue>>> {
         self.clean();
-        if rotate {
-            let result = self.data.get_mut(key);
-            if result.is_some() {
-                let mut record = result.unwrap();
-                if let Some(ref mut records) = *record {
-                    let element = records.pop_front().unwrap();
-                    records.push_back(element);
-                }
+        let now = Instant::now();
+        let expired = match self.data.get_mut(key) {
+            Some(entry) => entry.purge(now),
+            None => {
+                self.misses += 1;
+                return None;
+            }
+        };
+        if expired {
+            self.remove(key);
+            self.misses += 1;
+            return None;
+        }
+        self.hits += 1;
+        let keyref = self.usage[&self.data[key].last_used].clone();
+        self.touch(&keyref);
+        let entry = self.data.get_mut(key).unwrap();
+        Some(entry.records.as_mut().map(|records| {
+            if rotate {
+                let element = records.pop_front().unwrap();
+                records.push_back(element);
+            }
+            records.iter().map(|&(ref v, _)| v.clone()).collect()
+        }))
+    }
+
+    // Records of a key like query returns them, without counting or rotating
+    pub fn peek(&self, key: &TKey) -> Option<Option<Vec<TValue>>> {
+        let now = Instant::now();
+        let entry = match self.data.get(key) {
+            Some(entry) => entry,
+            None => return None
+        };
+        match entry.records {
+            Some(ref records) => {
+                let live: Vec<TValue> = records.iter().filter(|&&(_, expiry)| expiry > now).map(|&(ref v, _)| v.clone()).collect();
+                if live.is_empty() { None } else { Some(Some(live)) }
+            },
+            None if entry.negative_expiry > now => Some(None),
+            None => None
+        }
+    }
+
+    // All records with their expiry times, negative entries without value
+    pub fn entries(&self) -> Vec<(&TKey, Option<&TValue>, Instant)> {
+        let mut result = Vec::with_capacity(self.data.len());
+        for (key, entry) in self.data.iter() {
+            match entry.records {
+                Some(ref records) => {
+                    for &(ref value, expiry) in records.iter() {
+                        result.push((&**key, Some(value), expiry));
+                    }
+                },
+                None => result.push((&**key, None, entry.negative_expiry))
             }
         }
-        self.data.get(key)
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn cache(capacity: usize) -> Cache<String, u32> {
+        let mut cache = Cache::new(capacity);
+        cache.insert(String::from("a"), 1, Duration::from_secs(60));
+        cache.insert(String::from("b"), 2, Duration::from_secs(60));
+        cache
+    }
+
+    fn resolver() -> CachingResolver<usize> {
+        CachingResolver::new(IpConfig {
+            supported_versions: IP_V4 | IP_V6,
+            default_version: IP_V4
+        }, 1, 1)
+    }
+
+    #[test]
+    fn evicts_least_recently_inserted() {
+        let mut cache = cache(2);
+        cache.insert(String::from("c"), 3, Duration::from_secs(60));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.peek(&String::from("a")), None);
+        assert_eq!(cache.peek(&String::from("b")), Some(Some(vec![2])));
+        assert_eq!(cache.peek(&String::from("c")), Some(Some(vec![3])));
+    }
+
+    #[test]
+    fn query_marks_entry_as_used() {
+        let mut cache = cache(2);
+        assert_eq!(cache.query(&String::from("a"), false), Some(Some(vec![1])));
+        cache.insert(String::from("c"), 3, Duration::from_secs(60));
+        assert_eq!(cache.peek(&String::from("a")), Some(Some(vec![1])));
+        assert_eq!(cache.peek(&String::from("b")), None);
+        assert_eq!((cache.hits(), cache.misses()), (1, 0));
+    }
+
+    #[test]
+    fn negative_entries_are_evicted() {
+        let mut cache = cache(2);
+        cache.insert_negative(String::from("c"), Duration::from_secs(60));
+        assert_eq!(cache.peek(&String::from("a")), None);
+        assert_eq!(cache.peek(&String::from("c")), Some(None));
+        cache.insert(String::from("d"), 4, Duration::from_secs(60));
+        assert_eq!(cache.peek(&String::from("b")), None);
+        assert_eq!(cache.peek(&String::from("c")), Some(None));
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn additional_records_do_not_evict() {
+        let mut cache = cache(2);
+        cache.insert(String::from("a"), 3, Duration::from_secs(60));
+        cache.insert(String::from("a"), 1, Duration::from_secs(60));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.peek(&String::from("a")), Some(Some(vec![3, 1])));
+        assert_eq!(cache.peek(&String::from("b")), Some(Some(vec![2])));
+    }
+
+    #[test]
+    fn absolute_names_bypass_search_list() {
+        let ip_config = IpConfig {
+            supported_versions: IP_V4,
+            default_version: IP_V4
+        };
+        let mut resolver: CachingResolver<usize> = CachingResolver::new(ip_config, 1, 1);
+        resolver.search = vec![String::from("corp.local")];
+        assert_eq!(resolver.search_names(&String::from("example.com.")), vec![String::from("example.com.")]);
+        assert_eq!(resolver.search_names(&String::from("example.com")),
+                   vec![String::from("example.com."), String::from("example.com.corp.local.")]);
+        assert_eq!(resolver.search_names(&String::from("example")),
+                   vec![String::from("example.corp.local."), String::from("example.")]);
+    }
+
+    #[test]
+    fn dns_options() {
+        let mut resolver = resolver();
+        for option in ["timeout:3", "attempts:4", "ndots:2", "rotate"].iter() {
+            resolver.parse_dns_option(option);
+        }
+        assert_eq!((resolver.timeout, resolver.attempts, resolver.ndots), (Duration::from_secs(3), 4, 2));
+        match resolver.round_robin {
+            RoundRobin::Rotate => {},
+            _ => panic!("Expected rotation")
+        }
+
+        // Values are clamped to the limits of resolv.conf(5)
+        for option in ["timeout:0", "attempts:9", "ndots:20"].iter() {
+            resolver.parse_dns_option(option);
+        }
+        assert_eq!((resolver.timeout, resolver.attempts, resolver.ndots), (Duration::from_secs(1), 5, 15));
+        resolver.parse_dns_option("timeout:99");
+        resolver.parse_dns_option("attempts:0");
+        assert_eq!((resolver.timeout, resolver.attempts), (Duration::from_secs(30), 1));
+
+        // Unknown options and malformed values are ignored
+        for option in ["timeout", "attempts:x", "ndots:-1", "edns0", "rotate:1"].iter() {
+            resolver.parse_dns_option(option);
+        }
+        assert_eq!((resolver.timeout, resolver.attempts, resolver.ndots), (Duration::from_secs(30), 1, 15));
+    }
+
+    #[test]
+    fn dns_config() {
+        let mut resolver = resolver();
+        resolver.read_dns_config("# Comment\n\
+                                  nameserver 192.0.2.53\n\
+                                  nameserver 2001:db8::53\n\
+                                  nameserver invalid\n\
+                                  domain example.org\n\
+                                  search Corp.Local. example.com\n\
+                                  options ndots:3 attempts:1\n".as_bytes());
+        assert_eq!(resolver.servers, vec![SocketAddr::from_str("192.0.2.53:53").unwrap(),
+                                          SocketAddr::from_str("[2001:db8::53]:53").unwrap()]);
+        assert_eq!(resolver.search, vec![String::from("corp.local"), String::from("example.com")]);
+        assert_eq!((resolver.ndots, resolver.attempts), (3, 1));
+    }
+
+    #[test]
+    fn hosts() {
+        let mut resolver = resolver();
+        resolver.read_hosts("127.0.0.1 localhost\n\
+                             ::1 localhost ip6-localhost # Comment\n\
+                             # 192.0.2.9 commented.example\n\
+                             fe80::1%eth0 scoped.example\n\
+                             192.0.2.1 Whois.Example whois-alias.example.\n\
+                             192.0.2.2 whois.example\n".as_bytes());
+        let addresses = |resolver: &CachingResolver<usize>, name: &str| {
+            resolver.hosts.get(name).map(|a| (a.ipv4.clone(), a.ipv6.clone()))
+        };
+        let v4 = |s: &str| IpAddr::from_str(s).unwrap();
+        assert_eq!(addresses(&resolver, "localhost."), Some((vec![v4("127.0.0.1")], vec![v4("::1")])));
+        assert_eq!(addresses(&resolver, "ip6-localhost."), Some((vec![], vec![v4("::1")])));
+        assert_eq!(addresses(&resolver, "whois.example."), Some((vec![v4("192.0.2.1"), v4("192.0.2.2")], vec![])));
+        assert_eq!(addresses(&resolver, "whois-alias.example."), Some((vec![v4("192.0.2.1")], vec![])));
+        assert_eq!(addresses(&resolver, "scoped.example."), None);
+        assert_eq!(addresses(&resolver, "commented.example."), None);
+    }
+
+    #[test]
+    fn cname_expiry_is_saved() {
+        let ip_config = IpConfig {
+            supported_versions: IP_V4,
+            default_version: IP_V4
+        };
+        let path = env::temp_dir().join(format!("masswhois-cache-test-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let unix_now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        {
+            let mut file = File::create(path).unwrap();
+            writeln!(file, "CNAME live.example. target.example {}", unix_now + 3600).unwrap();
+            writeln!(file, "CNAME stale.example. target.example {}", unix_now - 1).unwrap();
+            writeln!(file, "CNAME old.example. target.example").unwrap();
+        }
+        let mut resolver: CachingResolver<usize> = CachingResolver::new(ip_config, 1, 1);
+        resolver.load_cache(path).unwrap();
+        assert_eq!(resolver.canonical_name("live.example"), Some(String::from("target.example")));
+        assert_eq!(resolver.canonical_name("stale.example"), None);
+        assert_eq!(resolver.canonical_name("old.example"), None);
+
+        resolver.save_cache(path).unwrap();
+        let mut saved = String::new();
+        File::open(path).unwrap().read_to_string(&mut saved).unwrap();
+        fs::remove_file(path).unwrap();
+        let words: Vec<&str> = saved.split_whitespace().collect();
+        assert_eq!(&words[..3], &["CNAME", "live.example.", "target.example"]);
+        let expiry = u64::from_str(words[3]).unwrap();
+        assert!(expiry >= unix_now + 3599 && expiry <= unix_now + 3601);
     }
 }
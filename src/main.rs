@@ -2,38 +2,69 @@ extern crate mio;
 extern crate netbuf;
 extern crate byteorder;
 #[macro_use]
-extern crate bitflags;
-#[macro_use]
 extern crate indoc;
+extern crate masswhois as resolver; // The library crate, which has the same name as the masswhois module
 
 pub mod masswhois;
-pub mod dnsutils;
+pub use resolver::dnsutils;
 
 use std::env;
 use std::str::FromStr;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::io;
 use std::io::{BufReader, BufRead, Write, BufWriter};
 use std::fs::File;
 use masswhois::*;
 use masswhois::handler::*;
+use masswhois::ratelimit::*;
+use masswhois::database::NetworkMatch;
+use masswhois::client::WHOIS_PORT;
+use dnsutils::RoundRobin;
 use std::process::exit;
+use std::time::Duration;
+use std::cmp::max;
+use std::collections::HashMap;
 
 fn main() {
     let mut args = env::args().skip(1);
     let mut infile: Option<String> = None;
     let mut outfile: Option<String> = None;
     let mut servers: Vec<IpAddr> = Default::default();
+    let mut resolvers: Vec<SocketAddr> = Default::default();
+    let mut dns_tcp = false;
+    let mut edns_payload: Option<u16> = Some(1232);
+    let mut dns_cache: Option<String> = None;
+    let mut dns_timeout: Option<Duration> = None;
+    let mut dns_attempts: Option<usize> = None;
+    let mut dns_rotate = false;
+    let mut dns_stats = false;
     let mut concurrency: usize = 5;
     let mut ip_config = IpConfig {
-        supported_versions: IP_V4,
+        supported_versions: IP_V4 | IP_V6,
         default_version: IP_V4
     };
     let mut infer_types = true;
     let mut infer_servers = true;
+    let mut address_source = AddressSource::DNS;
+    let mut host_overrides = HashMap::new();
+    let mut discovery = vec![DiscoveryStrategy::IANA, DiscoveryStrategy::DNS];
     let mut check_availability = false;
+    let mut dns_precheck = false;
     let mut stdout = false;
     let mut queries : Option<String> = None;
+    let mut timeouts = Timeouts {
+        connect: Duration::from_secs(10),
+        idle: Duration::from_secs(20),
+        total: Duration::from_secs(60)
+    };
+    let mut retry = RetryPolicy {
+        attempts: 3,
+        backoff: Duration::from_secs(1),
+        rotate: true
+    };
+    let mut rate_limits: RateLimits = Default::default();
+    let mut concurrency_limits: ConcurrencyLimits = Default::default();
+    let mut network_match = NetworkMatch::Default;
 
     loop {
         match args.next() {
@@ -47,11 +78,56 @@ fn main() {
                                Can be specified multiple times
                     -o FILE    File where binary output is written to
                     -i FILE    Query objects from file instead of using command line arguments
-                    --ip 4,6   IP version support. Preferred version first
+                    --ip 4,6   IP version support. Preferred version first (default: 4,6, formerly 4)
+
+                    --resolver IP[:PORT]  DNS resolver to use instead of those from /etc/resolv.conf
+                                          Can be specified multiple times
+                    --dns-tcp             Send all DNS queries over TCP
+                    --dns-timeout SECS    Time until a DNS query is sent again, doubled after every round
+                                          over all resolvers (default: from /etc/resolv.conf or 5)
+                    --dns-attempts N      Number of rounds over all resolvers (default: from /etc/resolv.conf or 2)
+                    --dns-rotate          Spread DNS queries over the resolvers randomly instead of asking
+                                          them in order
+                    --edns-payload BYTES  UDP payload size announced through EDNS, 0 disables EDNS (default: 1232)
+                    --dns-cache FILE      Load cached DNS records from the file at startup and save them at exit
+                    --dns-stats           Print the hits and misses of the DNS cache to stderr at exit
+
+                    --network-match exact|less  Request exactly matching or less specific
+                                                objects for network queries
+
+                    --connect-timeout SECS  Connection establishment timeout (default: 10)
+                    --idle-timeout SECS     Timeout while no data is received (default: 20)
+                    --timeout SECS          Total timeout per query (default: 60)
+
+                    --attempts N            Maximum number of attempts per query (default: 3)
+                    --retry-backoff SECS    Delay before retrying, doubled for every retry (default: 1)
+                    --retry-same-address    Do not move on to the next server address when retrying
+
+                    --rate SERVER=QPS[:BURST]  Query rate limit for a whois server
+                                               Can be specified multiple times
+                    --default-rate QPS[:BURST] Query rate limit for all other whois servers
+                    --cooldown SECS            Pause for servers which report exceeded limits (default: 60)
+
+                    --server-concurrency SERVER=N  Maximum number of concurrent lookups for a whois server
+                                                   name or IP address. Can be specified multiple times
+                    --default-server-concurrency N Maximum number of concurrent lookups for all other servers
+
+                    --server-addresses MODE Where to get the addresses of whois servers from (default: dns)
+                                            bundled: Use the bundled addresses, resolve unlisted servers
+                                            dns: Always resolve the server names
+                    --host NAME=IP[:PORT]   Address of a whois server, taking precedence over /etc/hosts, DNS and
+                                            the bundled addresses. Can be specified multiple times
 
                     --no-infer-types      Do not infer the query type
                     --no-infer-servers    Do not infer the query server
-                    --check-availability  Perform a domain availability check only.");
+                    --discovery LIST      Strategies for finding the server of TLDs unknown to the database,
+                                          comma-separated and tried in order (default: iana,dns)
+                                          iana: Ask whois.iana.org
+                                          dns: Use the CNAME target of <tld>.whois-servers.net
+                    --no-discover-servers Do not look for the server of TLDs unknown to the database
+                    --check-availability  Perform a domain availability check only.
+                    --dns-precheck        Look up NS records first, delegated domains are reported as unavailable
+                                          without asking whois. The deciding stage (DNS or WHOIS) is added to the output");
                     println!("{}", help);
                     exit(0);
                 },
@@ -60,19 +136,134 @@ fn main() {
                     let ip_addr = IpAddr::from_str(ip_str.as_ref()).expect("Invalid server argument. Must be an IP address.");
                     servers.push(ip_addr)
                 },
+                "--resolver" => {
+                    let resolver_str = args.next().expect("Missing resolver argument.");
+                    let resolver = match IpAddr::from_str(resolver_str.as_ref()) {
+                        Ok(ip) => SocketAddr::new(ip, 53),
+                        Err(_) => SocketAddr::from_str(resolver_str.as_ref()).expect("Invalid resolver argument. Must be IP[:PORT].")
+                    };
+                    resolvers.push(resolver);
+                },
+                "--edns-payload" => {
+                    let payload_str = args.next().expect("Missing payload argument.");
+                    edns_payload = match u16::from_str(payload_str.as_ref()).expect("Invalid payload argument.") {
+                        0 => None,
+                        payload => Some(max(payload, 512))
+                    };
+                },
+                "--dns-tcp" => {
+                    dns_tcp = true;
+                },
+                "--dns-timeout" => {
+                    let timeout_str = args.next().expect("Missing timeout argument.");
+                    dns_timeout = Some(Duration::from_secs(max(u64::from_str(timeout_str.as_ref()).expect("Invalid timeout argument."), 1)));
+                },
+                "--dns-attempts" => {
+                    let attempts_str = args.next().expect("Missing attempts argument.");
+                    dns_attempts = Some(max(usize::from_str(attempts_str.as_ref()).expect("Invalid attempts argument."), 1));
+                },
+                "--dns-rotate" => {
+                    dns_rotate = true;
+                },
+                "--dns-cache" => {
+                    dns_cache = Some(args.next().expect("Missing cache file argument."));
+                },
+                "--dns-stats" => {
+                    dns_stats = true;
+                },
                 "--no-infer-types" => {
                     infer_types = false;
                 },
                 "--no-infer-servers" => {
                     infer_servers = false;
                 },
+                "--server-addresses" => {
+                    let source_str = args.next().expect("Missing server addresses argument.");
+                    address_source = AddressSource::from_str(source_str.as_ref()).expect("Invalid server addresses argument. Must be bundled or dns.");
+                },
+                "--host" => {
+                    let host_str = args.next().expect("Missing host argument.");
+                    let mut fields = host_str.splitn(2, '=');
+                    let name = String::from(fields.next().unwrap()).to_lowercase();
+                    let address_str = fields.next().expect("Invalid host argument. Must be NAME=IP[:PORT].");
+                    let address = match IpAddr::from_str(address_str) {
+                        Ok(ip) => SocketAddr::new(ip, WHOIS_PORT),
+                        Err(_) => SocketAddr::from_str(address_str).expect("Invalid host argument. Must be NAME=IP[:PORT].")
+                    };
+                    host_overrides.insert(name, address);
+                },
+                "--discovery" => {
+                    let discovery_str = args.next().expect("Missing discovery argument.");
+                    discovery = discovery_str.split(',').map(|s| DiscoveryStrategy::from_str(s).expect("Invalid discovery argument. Must be a list of iana and dns.")).collect();
+                },
+                "--no-discover-servers" => {
+                    discovery.clear();
+                },
                 "--check-availability" => {
                     check_availability = true;
                 },
+                "--dns-precheck" => {
+                    dns_precheck = true;
+                },
                 "-c" | "--concurrency" => {
                     let concurrency_str = args.next().expect("Missing concurrency argument.");
                     concurrency = usize::from_str(concurrency_str.as_ref()).expect("Invalid concurrency argument.");
                 },
+                "--connect-timeout" => {
+                    let timeout_str = args.next().expect("Missing timeout argument.");
+                    timeouts.connect = Duration::from_secs(u64::from_str(timeout_str.as_ref()).expect("Invalid timeout argument."));
+                },
+                "--idle-timeout" => {
+                    let timeout_str = args.next().expect("Missing timeout argument.");
+                    timeouts.idle = Duration::from_secs(u64::from_str(timeout_str.as_ref()).expect("Invalid timeout argument."));
+                },
+                "--timeout" => {
+                    let timeout_str = args.next().expect("Missing timeout argument.");
+                    timeouts.total = Duration::from_secs(u64::from_str(timeout_str.as_ref()).expect("Invalid timeout argument."));
+                },
+                "--attempts" => {
+                    let attempts_str = args.next().expect("Missing attempts argument.");
+                    retry.attempts = usize::from_str(attempts_str.as_ref()).expect("Invalid attempts argument.");
+                },
+                "--retry-backoff" => {
+                    let backoff_str = args.next().expect("Missing backoff argument.");
+                    retry.backoff = Duration::from_secs(u64::from_str(backoff_str.as_ref()).expect("Invalid backoff argument."));
+                },
+                "--retry-same-address" => {
+                    retry.rotate = false;
+                },
+                "--rate" => {
+                    let rate_str = args.next().expect("Missing rate argument.");
+                    let mut fields = rate_str.splitn(2, '=');
+                    let server = String::from(fields.next().unwrap()).to_lowercase();
+                    let rate = Rate::from_str(fields.next().expect("Invalid rate argument. Must be SERVER=QPS[:BURST].").as_ref())
+                        .expect("Invalid rate argument. Must be SERVER=QPS[:BURST].");
+                    rate_limits.servers.insert(server, rate);
+                },
+                "--default-rate" => {
+                    let rate_str = args.next().expect("Missing rate argument.");
+                    rate_limits.default = Some(Rate::from_str(rate_str.as_ref()).expect("Invalid rate argument. Must be QPS[:BURST]."));
+                },
+                "--cooldown" => {
+                    let cooldown_str = args.next().expect("Missing cooldown argument.");
+                    rate_limits.cooldown = Duration::from_secs(u64::from_str(cooldown_str.as_ref()).expect("Invalid cooldown argument."));
+                },
+                "--server-concurrency" => {
+                    let cap_str = args.next().expect("Missing concurrency argument.");
+                    let mut fields = cap_str.splitn(2, '=');
+                    let server = String::from(fields.next().unwrap()).to_lowercase();
+                    // A cap of 0 would hold back the queries for the server forever
+                    let cap = usize::from_str(fields.next().expect("Invalid concurrency argument. Must be SERVER=N.").as_ref())
+                        .ok().filter(|&cap| cap > 0)
+                        .expect("Invalid concurrency argument. Must be SERVER=N with N at least 1.");
+                    concurrency_limits.servers.insert(server, cap);
+                },
+                "--default-server-concurrency" => {
+                    let cap_str = args.next().expect("Missing concurrency argument.");
+                    let cap = usize::from_str(cap_str.as_ref()).ok().filter(|&cap| cap > 0)
+                        .expect("Invalid concurrency argument. Must be at least 1.");
+                    concurrency_limits.default = Some(cap);
+                },
                 "-o" | "--outfile" => {
                     if outfile.is_some() {
                         panic!("Invalid parameter.");
@@ -112,6 +303,16 @@ fn main() {
                         }
                     };
                 },
+                "--network-match" => {
+                    let match_str = args.next().expect("Missing network match argument.");
+                    network_match = match match_str.as_ref() {
+                        "exact" => NetworkMatch::Exact,
+                        "less" => NetworkMatch::LessSpecific,
+                        &_ => {
+                            panic!("Invalid network match argument.");
+                        }
+                    };
+                },
                 "-i" | "--infile" => {
                     if infile.is_some() {
                         panic!("Invalid parameter.");
@@ -149,7 +350,7 @@ fn main() {
         if stdout {
             Box::new(WhoisOutputReadable { writer: writer })
         } else {
-            Box::new(WhoisOutputBinary { writer: writer })
+            Box::new(WhoisOutputBinary::new(writer))
         }
     } else {
         Box::new(WhoisOutputAvailability { writer: writer })
@@ -160,6 +361,31 @@ fn main() {
         Some(q) => Box::new(WhoisRawQueryCmd::new(q))
     };
 
-    let mut masswhois: MassWhois = MassWhois::new(concurrency, ip_config, infer_servers, r, binary_output, infer_types, check_availability);
+    let mut masswhois: MassWhois = MassWhois::new(concurrency, ip_config, servers, resolvers, dns_tcp, edns_payload, infer_servers, address_source, host_overrides, discovery, r, binary_output, infer_types, check_availability, dns_precheck, timeouts, retry, rate_limits, concurrency_limits);
+    masswhois.db.network_match = network_match;
+    if let Some(timeout) = dns_timeout {
+        masswhois.resolver.set_timeout(timeout);
+    }
+    if let Some(attempts) = dns_attempts {
+        masswhois.resolver.set_attempts(attempts);
+    }
+    if dns_rotate {
+        masswhois.resolver.set_round_robin(RoundRobin::Random);
+    }
+    if let Some(ref path) = dns_cache {
+        // There is no cache file before the first run
+        if let Err(e) = masswhois.resolver.load_cache(path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                panic!("Error reading DNS cache: {}", e);
+            }
+        }
+    }
     masswhois.start();
+    if let Some(ref path) = dns_cache {
+        masswhois.resolver.save_cache(path).expect("Error writing DNS cache.");
+    }
+    if dns_stats {
+        let (hits, misses) = masswhois.resolver.cache_statistics();
+        eprintln!("DNS cache: {} hits, {} misses", hits, misses);
+    }
 }
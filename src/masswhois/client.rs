@@ -1,14 +1,21 @@
 extern crate mio;
 extern crate netbuf;
 
-use mio::Token;
+use mio::{Token, Poll, Ready, PollOpt};
 use mio::tcp::TcpStream;
+use mio::unix::UnixReady;
 use netbuf::Buf;
 use std::net::{IpAddr, SocketAddr};
-use std::io::Write;
+use std::io;
+use std::io::{Write, ErrorKind};
 use masswhois::query::WhoisQuery;
-use masswhois::Status;
+use masswhois::{DiscoveryStrategy, Status, Timeouts};
 use std::string::ToString;
+use std::time::{Duration, Instant};
+use std::collections::VecDeque;
+
+static CONNECTION_ATTEMPT_DELAY_MS: u64 = 250; // Recommended by RFC 8305
+pub static WHOIS_PORT: u16 = 43;
 
 pub enum Availability {
     AVAILABLE, UNAVAILABLE, UNKNOWN
@@ -24,27 +31,129 @@ impl ToString for Availability {
     }
 }
 
+// The stage of the availability check which has decided
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum Stage {
+    DNS, // The domain is delegated
+    Whois
+}
+
+impl ToString for Stage {
+    fn to_string(&self) -> String {
+        match *self {
+            Stage::DNS => String::from("DNS"),
+            Stage::Whois => String::from("WHOIS")
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum Outcome {
+    Complete, ConnectTimeout, IdleTimeout, Timeout,
+    ConnectionRefused, ConnectionReset, Unreachable, NoAddress, Unresolvable, Empty, RateLimited, InvalidQuery, Error
+}
+
+impl Outcome {
+    // Whether another attempt of the same query may succeed
+    pub fn is_transient(&self) -> bool {
+        match *self {
+            Outcome::ConnectTimeout | Outcome::ConnectionRefused | Outcome::ConnectionReset
+                | Outcome::Unreachable | Outcome::Empty => true,
+            _ => false
+        }
+    }
+
+    // Identifies the outcome in the binary output
+    pub fn code(&self) -> u8 {
+        match *self {
+            Outcome::Complete => 0,
+            Outcome::ConnectTimeout => 1,
+            Outcome::IdleTimeout => 2,
+            Outcome::Timeout => 3,
+            Outcome::ConnectionRefused => 4,
+            Outcome::ConnectionReset => 5,
+            Outcome::Unreachable => 6,
+            Outcome::NoAddress => 7,
+            Outcome::Unresolvable => 8,
+            Outcome::Empty => 9,
+            Outcome::RateLimited => 10,
+            Outcome::InvalidQuery => 11,
+            Outcome::Error => 255
+        }
+    }
+
+    pub fn from_error(error: &io::Error) -> Outcome {
+        match error.kind() {
+            ErrorKind::ConnectionRefused => Outcome::ConnectionRefused,
+            ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe => Outcome::ConnectionReset,
+            ErrorKind::NetworkUnreachable | ErrorKind::HostUnreachable => Outcome::Unreachable,
+            ErrorKind::TimedOut => Outcome::ConnectTimeout,
+            _ => Outcome::Error
+        }
+    }
+}
+
+impl ToString for Outcome {
+    fn to_string(&self) -> String {
+        match *self {
+            Outcome::Complete => String::from("COMPLETE"),
+            Outcome::ConnectTimeout => String::from("CONNECT_TIMEOUT"),
+            Outcome::IdleTimeout => String::from("IDLE_TIMEOUT"),
+            Outcome::Timeout => String::from("TIMEOUT"),
+            Outcome::ConnectionRefused => String::from("CONNECTION_REFUSED"),
+            Outcome::ConnectionReset => String::from("CONNECTION_RESET"),
+            Outcome::Unreachable => String::from("UNREACHABLE"),
+            Outcome::NoAddress => String::from("NO_ADDRESS"),
+            Outcome::Unresolvable => String::from("UNRESOLVABLE"),
+            Outcome::Empty => String::from("EMPTY"),
+            Outcome::RateLimited => String::from("RATE_LIMITED"),
+            Outcome::InvalidQuery => String::from("INVALID_QUERY"),
+            Outcome::Error => String::from("ERROR")
+        }
+    }
+}
+
 pub struct WhoisClient {
-    pub stream: TcpStream,
+    pub stream: Option<TcpStream>,
     pub token: Token,
     pub query_str: String,
     pub inbuf: Buf,
     pub outbuf: Buf,
     pub terminated: bool,
     pub dns_tries: usize,
-    pub error: bool,
     pub query: WhoisQuery,
     pub referral_count: usize,
     pub server: Option<String>,
     pub address: Option<IpAddr>,
+    pub port: u16, // Shared by all addresses of the server
+    pub acquired: Vec<String>, // Concurrency limiter keys held by the connection
+    pub racing: Vec<(TcpStream, IpAddr)>, // Connection attempts to further addresses racing against the stream
+    pub alternatives: VecDeque<IpAddr>, // Addresses of the server which have not been tried yet
+    pub next_attempt: Instant, // When to race the next address if no handshake has completed by then
     pub status: Status,
-    pub availability: Availability
+    pub discovery: bool, // Whether the query asks for the whois server of a TLD
+    pub discovered_by: Option<DiscoveryStrategy>, // How the server was found if it was not known beforehand
+    pub availability: Availability,
+    pub decided_by: Option<Stage>, // Only set if availability is checked through DNS first
+    pub outcome: Outcome,
+    pub serial: usize,
+    pub connected: bool,
+    pub started: Instant,
+    pub last_activity: Instant
 }
 
 impl WhoisClient {
-    pub fn new(concurrency_index: usize, query: WhoisQuery, query_str: String, address: Option<IpAddr>, server: Option<String>) -> Self {
-        let addr  = SocketAddr::new(address.expect("Non-IP address not implemented."), 43);
-        let stream = TcpStream::connect(&addr).expect("Failed to connect.");
+    pub fn new(concurrency_index: usize, query: WhoisQuery, query_str: String, address: Option<IpAddr>, port: u16, server: Option<String>) -> Self {
+        let (stream, outcome) = match address {
+            // A server name without address could not be resolved
+            None if server.is_some() => (None, Outcome::Unresolvable),
+            None => (None, Outcome::NoAddress),
+            Some(ip) => match TcpStream::connect(&SocketAddr::new(ip, port)) {
+                Ok(stream) => (Some(stream), Outcome::Complete),
+                Err(e) => (None, Outcome::from_error(&e))
+            }
+        };
+        let now = Instant::now();
         let mut outbuf = Buf::new();
         outbuf.write_all(query_str.as_bytes()).expect("Failed to write to outfile.");
         outbuf.write_all(String::from("\n").as_bytes()).expect("Failed to write to outfile.");
@@ -56,13 +165,204 @@ impl WhoisClient {
             query_str: query_str,
             terminated: false,
             dns_tries: 0,
-            error: false,
             query: query,
             referral_count: 0,
             server: server,
             address: address,
+            port: port,
+            acquired: Vec::new(),
+            racing: Vec::new(),
+            alternatives: VecDeque::new(),
+            next_attempt: now + Duration::from_millis(CONNECTION_ATTEMPT_DELAY_MS),
             status: Status::Initial,
-            availability: Availability::UNKNOWN
+            discovery: false,
+            discovered_by: None,
+            availability: Availability::UNKNOWN,
+            decided_by: None,
+            outcome: outcome,
+            serial: 0,
+            connected: false,
+            started: now,
+            last_activity: now
+        }
+    }
+
+    pub fn register(&self, poll: &Poll, stream: &TcpStream) {
+        let events = Ready::readable() | Ready::writable() | UnixReady::hup() | UnixReady::error();
+        poll.register(stream, self.token, events, PollOpt::edge()).expect("Failed to register poll.");
+    }
+
+    pub fn deregister(&mut self, poll: &Poll) {
+        if let Some(ref stream) = self.stream {
+            poll.deregister(stream).ok();
+        }
+        for (stream, _) in self.racing.drain(..) {
+            poll.deregister(&stream).ok();
+        }
+    }
+
+    // Start a connection attempt to the next address which has not been tried yet, in addition to the running
+    // ones (RFC 8305). It replaces the stream if there is none. Addresses which cannot be connected to are skipped.
+    pub fn attempt(&mut self, poll: &Poll, now: Instant) {
+        self.next_attempt = now + Duration::from_millis(CONNECTION_ATTEMPT_DELAY_MS);
+        while let Some(address) = self.alternatives.pop_front() {
+            match TcpStream::connect(&SocketAddr::new(address, self.port)) {
+                Ok(stream) => {
+                    self.register(poll, &stream);
+                    if self.stream.is_none() {
+                        self.stream = Some(stream);
+                        self.address = Some(address);
+                        self.outcome = Outcome::Complete;
+                    } else {
+                        self.racing.push((stream, address));
+                    }
+                    return;
+                },
+                Err(e) => self.outcome = Outcome::from_error(&e)
+            }
+        }
+    }
+
+    // Check the connection attempts after an event. The first attempt which has completed its handshake becomes
+    // the stream and the others are abandoned. If all of them have failed, the next address is tried right away.
+    // Returns the last error if no address is left.
+    pub fn settle(&mut self, poll: &Poll, now: Instant) -> Option<io::Error> {
+        let mut attempts: Vec<(TcpStream, IpAddr)> = Vec::with_capacity(self.racing.len() + 1);
+        if let (Some(stream), Some(address)) = (self.stream.take(), self.address) {
+            attempts.push((stream, address));
+        }
+        attempts.extend(self.racing.drain(..));
+
+        let mut alive = Vec::with_capacity(attempts.len());
+        let mut failed = None;
+        for (stream, address) in attempts {
+            let error = match stream.take_error() {
+                Ok(e) => e,
+                Err(e) => Some(e)
+            };
+            match error {
+                None => alive.push((stream, address)),
+                Some(e) => {
+                    if let Some((previous, _, _)) = failed.take() {
+                        poll.deregister(&previous).ok();
+                    }
+                    failed = Some((stream, address, e));
+                }
+            }
+        }
+
+        if alive.is_empty() {
+            self.attempt(poll, now);
+            if self.stream.is_none() {
+                // Keep the failed stream so that it is deregistered like any other once the client is finished
+                let (stream, address, e) = failed.unwrap();
+                self.stream = Some(stream);
+                self.address = Some(address);
+                return Some(e);
+            }
+        }
+        if let Some((stream, _, _)) = failed {
+            poll.deregister(&stream).ok();
+        }
+        if let Some(index) = alive.iter().position(|&(ref stream, _)| stream.peer_addr().is_ok()) {
+            let winner = alive.remove(index);
+            for (stream, _) in alive.drain(..) {
+                poll.deregister(&stream).ok();
+            }
+            alive.push(winner);
+            self.alternatives.clear();
+            self.connected = true;
+        }
+        let mut alive = alive.into_iter();
+        if self.stream.is_none() {
+            let (stream, address) = alive.next().unwrap();
+            self.stream = Some(stream);
+            self.address = Some(address);
+        }
+        self.racing.extend(alive);
+        None
+    }
+
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        match self.stream {
+            Some(ref stream) => match stream.take_error() {
+                Ok(e) => e,
+                Err(e) => Some(e)
+            },
+            None => None
+        }
+    }
+
+    pub fn send(&mut self) -> io::Result<()> {
+        if let Some(ref mut stream) = self.stream {
+            while self.outbuf.len() > 0 {
+                match self.outbuf.write_to(stream) {
+                    Ok(_) => {},
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(ref e) if e.kind() == ErrorKind::Interrupted => {},
+                    Err(e) => return Err(e)
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Read everything available. Returns whether the server has closed the connection.
+    pub fn receive(&mut self) -> io::Result<bool> {
+        match self.stream {
+            Some(ref mut stream) => loop {
+                match self.inbuf.read_from(stream) {
+                    Ok(0) => return Ok(true),
+                    Ok(_) => {},
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                    Err(ref e) if e.kind() == ErrorKind::Interrupted => {},
+                    Err(e) => return Err(e)
+                }
+            },
+            None => Ok(true)
+        }
+    }
+
+    fn elapsed(since: Instant, now: Instant) -> Duration {
+        if now > since { now - since } else { Duration::from_secs(0) }
+    }
+
+    pub fn timeout(&self, timeouts: &Timeouts, now: Instant) -> Option<Outcome> {
+        let total = WhoisClient::elapsed(self.started, now);
+        if !self.connected && total >= timeouts.connect {
+            Some(Outcome::ConnectTimeout)
+        } else if total >= timeouts.total {
+            Some(Outcome::Timeout)
+        } else if WhoisClient::elapsed(self.last_activity, now) >= timeouts.idle {
+            Some(Outcome::IdleTimeout)
+        } else {
+            None
+        }
+    }
+
+    // Time left until the earliest deadline applying to this client
+    pub fn remaining(&self, timeouts: &Timeouts, now: Instant) -> Duration {
+        let total = WhoisClient::elapsed(self.started, now);
+        let idle = WhoisClient::elapsed(self.last_activity, now);
+        let mut remaining = timeouts.total.checked_sub(total).unwrap_or(Duration::from_secs(0));
+        if let Some(d) = timeouts.idle.checked_sub(idle) {
+            if d < remaining {
+                remaining = d;
+            }
+        }
+        if !self.connected {
+            if let Some(d) = timeouts.connect.checked_sub(total) {
+                if d < remaining {
+                    remaining = d;
+                }
+            }
+            if !self.alternatives.is_empty() {
+                let d = WhoisClient::elapsed(now, self.next_attempt);
+                if d < remaining {
+                    remaining = d;
+                }
+            }
         }
+        remaining
     }
 }
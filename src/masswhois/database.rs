@@ -1,11 +1,13 @@
 use std::net::IpAddr;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::str;
 use std::ops::Range;
 use std::str::FromStr;
+use std::cmp::Ordering;
 use masswhois::*;
 use masswhois::query::*;
 use masswhois::client::{WhoisClient, Availability};
+use dnsutils::Addresses;
 extern crate regex;
 use self::regex::bytes::Regex;
 
@@ -19,14 +21,38 @@ static MAP_SERVER_QUERY: &'static str = include_str!("../../data/server_query.tx
 static MAP_SERVER_REFERRAL: &'static str = include_str!("../../data/server_referral.txt");
 static MAP_SERVER_AVAILABILITY: &'static str = include_str!("../../data/domain_availability.txt");
 static MAP_ASN_SERVER: &'static str = include_str!("../../data/asn_server.txt");
+static MAP_SERVER_RATE_LIMIT: &'static str = include_str!("../../data/rate_limit.txt");
+static MAP_IPV4_SERVER: &'static str = include_str!("../../data/ipv4_server.txt");
+static MAP_IPV6_SERVER: &'static str = include_str!("../../data/ipv6_server.txt");
+static MAP_SERVER_NETWORK_MATCH: &'static str = include_str!("../../data/network_match.txt");
+static MAP_SERVER_IDN: &'static str = include_str!("../../data/server_idn.txt");
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum NetworkMatch {
+    Default, // Leave the choice of matching objects to the server
+    Exact, // Only objects for exactly the queried network
+    LessSpecific // Objects covering the queried network
+}
+
+static DISCOVERY_SERVER: &'static str = r"(?m)^\s*(?:refer|whois):\s+([0-9A-Za-z._-]+)\s*$";
 
 pub struct WhoisDatabase {
     pub map_domain_servers: HashMap<String, String>, // map domain to whois server
+    pub undiscoverable_domains: HashSet<String>, // TLDs for which no discovery strategy found a whois server
+    pub discovered_domains: HashMap<String, DiscoveryStrategy>, // TLDs whose server has been discovered, and how
+    pub dns_aliases: HashMap<String, String>, // map whois-servers.net alias to TLD
+    pub discovery_server: Regex,
     pub map_server_ips: HashMap<String, Vec<IpAddr>>, // map whois server name to addresses
     pub map_server_query: HashMap<(WhoisQueryType, String), (String, String)>,
     pub map_server_referral: HashMap<String, Regex>,
     pub general_availability: LinkedList<Regex>,
-    pub asn_map: AsnMap
+    pub map_server_rate_limit: HashMap<String, LinkedList<Regex>>,
+    pub general_rate_limit: LinkedList<Regex>,
+    pub map_server_network_match: HashMap<String, (String, String)>, // (exact, less specific) flags
+    pub network_match: NetworkMatch,
+    pub idn_utf8_servers: HashSet<String>, // Servers expecting domains as U-labels instead of A-labels
+    pub asn_map: AsnMap,
+    pub ip_map: IpMap
 }
 
 pub struct AsnMap {
@@ -83,21 +109,84 @@ impl AsnMap {
     }
 }
 
+// Maps address blocks to the whois server of the registry they are allocated to
+pub struct IpMap {
+    table: Vec<(IpAddr, IpAddr, String)> // (first, last, server) by first address, blocks after the blocks containing them
+}
+
+impl IpMap {
+    pub fn load() -> Self {
+        IpMap::parse(MAP_IPV4_SERVER.lines().chain(MAP_IPV6_SERVER.lines()))
+    }
+
+    fn parse<'a, I: Iterator<Item = &'a str>>(lines: I) -> Self {
+        let mut map = IpMap {
+            table: Default::default()
+        };
+
+        for l in lines {
+            let trimmed: String = String::from(l.trim());
+            if trimmed == String::from("") || trimmed.starts_with("#") {
+                continue;
+            }
+            let mut split = trimmed.split_whitespace();
+            let network = IpNetwork::from_str(split.next().unwrap()).expect("Invalid network within IP server file");
+            let server = String::from(split.next().unwrap());
+            map.table.push((network.first(), network.last(), server));
+        }
+        // Blocks starting at the same address are ordered from the least to the most specific one
+        map.table.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+
+        map
+    }
+
+    pub fn find(&self, ip: &IpAddr) -> String {
+        self.find_block(ip, ip)
+    }
+
+    // Server of the most specific block containing all addresses from first to last
+    pub fn find_block(&self, first: &IpAddr, last: &IpAddr) -> String {
+        let end = match self.table.binary_search_by(|entry| entry.0.cmp(first).then(Ordering::Less)) {
+            Ok(index) | Err(index) => index
+        };
+        // Blocks containing the addresses start before them, the most specific one comes last
+        for &(ref block_first, ref block_last, ref server) in self.table[..end].iter().rev() {
+            if block_first <= first && last <= block_last {
+                return server.clone();
+            }
+        }
+        String::from(SERVER_ARIN)
+    }
+}
+
 impl WhoisDatabase {
     pub fn new(ip_config: &IpConfig) -> WhoisDatabase {
         let mut result = WhoisDatabase {
             map_domain_servers: Default::default(),
+            undiscoverable_domains: Default::default(),
+            discovered_domains: Default::default(),
+            dns_aliases: Default::default(),
+            discovery_server: Regex::new(DISCOVERY_SERVER).unwrap(),
             map_server_ips: Default::default(),
             map_server_query: Default::default(),
             map_server_referral: Default::default(),
             general_availability: Default::default(),
-            asn_map: AsnMap::load()
+            map_server_rate_limit: Default::default(),
+            general_rate_limit: Default::default(),
+            map_server_network_match: Default::default(),
+            network_match: NetworkMatch::Default,
+            idn_utf8_servers: Default::default(),
+            asn_map: AsnMap::load(),
+            ip_map: IpMap::load()
         };
         result.read_domain_servers();
         result.read_server_ips(ip_config);
         result.read_server_queries();
         result.read_server_referrals();
         result.read_server_availability();
+        result.read_server_rate_limits();
+        result.read_server_network_match();
+        result.read_server_idn();
         result
     }
 
@@ -134,6 +223,55 @@ impl WhoisDatabase {
         }
     }
 
+    // Whois server of a TLD according to the response of IANA
+    pub fn discovered_server(&self, client: &WhoisClient) -> Option<String> {
+        let captures = self.discovery_server.captures(client.inbuf.as_ref())?;
+        let server = String::from_utf8(captures.get(1)?.as_bytes().to_vec()).ok()?;
+        Some(server.to_lowercase())
+    }
+
+    // Remember the result of a server discovery for the rest of the run
+    pub fn add_discovered_server(&mut self, tld: String, server: String, strategy: DiscoveryStrategy) {
+        if strategy == DiscoveryStrategy::DNS {
+            self.dns_aliases.insert(server.clone(), tld.clone());
+        }
+        self.map_domain_servers.insert(tld.clone(), server);
+        self.discovered_domains.insert(tld, strategy);
+    }
+
+    // Replace a whois-servers.net alias by the name it points to. Returns false for other server names.
+    pub fn resolve_alias(&mut self, alias: &String, canonical: &String) -> bool {
+        match self.dns_aliases.get(alias) {
+            Some(tld) => {
+                self.map_domain_servers.insert(tld.clone(), canonical.clone());
+                true
+            },
+            None => false
+        }
+    }
+
+    // Forget a whois-servers.net alias which could not be resolved so that the TLD is discovered again.
+    // Returns false for other server names.
+    pub fn drop_alias(&mut self, alias: &String) -> bool {
+        let tld = match self.dns_aliases.get(alias) {
+            Some(tld) => tld.clone(),
+            None => return false
+        };
+        if self.map_domain_servers.get(&tld) == Some(alias) {
+            self.map_domain_servers.remove(&tld);
+            self.discovered_domains.remove(&tld);
+        }
+        true
+    }
+
+    // How the server responsible for a domain query has been discovered, if it was not known beforehand
+    pub fn discovery_strategy(&self, query: &WhoisQuery) -> Option<DiscoveryStrategy> {
+        match *query {
+            WhoisQuery::Domain(ref x) => self.discovered_domains.get(x.rsplit('.').next().unwrap()).cloned(),
+            _ => None
+        }
+    }
+
     fn read_server_referrals(&mut self) {
         for l in MAP_SERVER_REFERRAL.lines() {
             let trimmed: String = String::from(l.trim());
@@ -169,6 +307,37 @@ impl WhoisDatabase {
         Availability::UNAVAILABLE
     }
 
+    fn read_server_rate_limits(&mut self) {
+        for l in MAP_SERVER_RATE_LIMIT.lines() {
+            let trimmed: String = String::from(l.trim());
+            if trimmed == String::from("") || trimmed.starts_with("#") {
+                continue;
+            }
+            let space_pos = trimmed.find(' ').expect("Invalid line within rate limit file");
+            let server: String = trimmed.chars().take(space_pos).collect();
+            let rest: String = trimmed.chars().skip(space_pos + 1).collect();
+            let expr = Regex::new(rest.as_str()).expect("Invalid regular expression.");
+            if server == "*" {
+                self.general_rate_limit.push_back(expr);
+            } else {
+                self.map_server_rate_limit.entry(server).or_insert(LinkedList::new()).push_back(expr);
+            }
+        }
+    }
+
+    // Whether the response indicates that the server throttled or denied the query
+    pub fn rate_limited(&self, client: &WhoisClient) -> bool {
+        let data = client.inbuf.as_ref();
+        if let Some(ref server) = client.server {
+            if let Some(exprs) = self.map_server_rate_limit.get(server) {
+                if exprs.iter().any(|r| r.is_match(data)) {
+                    return true;
+                }
+            }
+        }
+        self.general_rate_limit.iter().any(|r| r.is_match(data))
+    }
+
     fn read_server_queries(&mut self) {
         for l in MAP_SERVER_QUERY.lines() {
             let trimmed: String = String::from(l.trim());
@@ -180,8 +349,14 @@ impl WhoisDatabase {
             let rest: String = l.chars().skip(space_pos + 1).take(trimmed.len() - (space_pos + 1)).collect();
             let domain_pos = rest.find("$domain");
             let asn_pos = rest.find("$asn");
+            let ip_pos = rest.find("$ip");
+            let network_pos = rest.find("$network");
             let (qtype, len, pos) = if domain_pos.is_some() {
                 (WhoisQueryType::Domain, 7, domain_pos.unwrap())
+            } else if network_pos.is_some() {
+                (WhoisQueryType::Network, 8, network_pos.unwrap())
+            } else if ip_pos.is_some() {
+                (WhoisQueryType::IpAddr, 3, ip_pos.unwrap())
             } else {
                 (WhoisQueryType::AS, 4, asn_pos.expect("Invalid line within server query file"))
             };
@@ -191,6 +366,57 @@ impl WhoisDatabase {
         }
     }
 
+    fn read_server_network_match(&mut self) {
+        for l in MAP_SERVER_NETWORK_MATCH.lines() {
+            let trimmed: String = String::from(l.trim());
+            if trimmed == String::from("") || trimmed.starts_with("#") {
+                continue;
+            }
+            let mut fields = trimmed.split_whitespace();
+            let server = String::from(fields.next().unwrap());
+            let exact = String::from(fields.next().expect("Invalid line within network match file"));
+            let less_specific = String::from(fields.next().expect("Invalid line within network match file"));
+            self.map_server_network_match.insert(server, (exact, less_specific));
+        }
+    }
+
+    // Flag selecting the configured kind of network match, including the separating space
+    fn network_match_flag(&self, server: &String) -> String {
+        match (self.network_match, self.map_server_network_match.get(server)) {
+            (NetworkMatch::Exact, Some(&(ref exact, _))) => exact.clone() + " ",
+            (NetworkMatch::LessSpecific, Some(&(_, ref less_specific))) => less_specific.clone() + " ",
+            _ => String::from("")
+        }
+    }
+
+    fn read_server_idn(&mut self) {
+        for l in MAP_SERVER_IDN.lines() {
+            let trimmed: String = String::from(l.trim());
+            if trimmed == String::from("") || trimmed.starts_with("#") {
+                continue;
+            }
+            let mut fields = trimmed.split_whitespace();
+            let server = String::from(fields.next().unwrap()).to_lowercase();
+            match fields.next() {
+                Some("utf-8") => {
+                    self.idn_utf8_servers.insert(server);
+                },
+                Some("ace") => {},
+                _ => panic!("Invalid line within server IDN file")
+            }
+        }
+    }
+
+    // The queried object in the representation expected by the server
+    fn query_object(&self, query: &WhoisQuery, server: &String) -> String {
+        if self.idn_utf8_servers.contains(server) {
+            if let Some(unicode) = query.unicode() {
+                return unicode;
+            }
+        }
+        query.to_string()
+    }
+
     fn read_domain_servers(&mut self) {
         for l in MAP_DOMAIN_SERVER.lines() {
             let trimmed: String = String::from(l.trim());
@@ -241,32 +467,21 @@ impl WhoisDatabase {
 
     pub fn get_query(&self, query: &WhoisQuery, server: &String) -> String {
         match *query {
-            WhoisQuery::Domain(_) => {
+            WhoisQuery::Domain(_) | WhoisQuery::IpAddr(_) | WhoisQuery::AS(_) | WhoisQuery::Network(_) | WhoisQuery::Range(_) => {
                 let q = (query.get_type(), server.clone());
                 let server_query = self.map_server_query.get(&q);
                 if server_query.is_some() {
                     let &(ref prefix, ref suffix) = server_query.unwrap();
                     let mut query_string = prefix.clone();
-                    query_string += &query.to_string();
-                    query_string += &suffix;
-                    query_string
-                }
-                else {
-                    query.to_string() + "\n"
-                }
-            },
-            WhoisQuery::AS(_) => {
-                let q = (query.get_type(), server.clone());
-                let server_query = self.map_server_query.get(&q);
-                if server_query.is_some() {
-                    let &(ref prefix, ref suffix) = server_query.unwrap();
-                    let mut query_string = prefix.clone();
-                    query_string += &query.to_string();
+                    if query.get_type() == WhoisQueryType::Network {
+                        query_string += &self.network_match_flag(server);
+                    }
+                    query_string += &self.query_object(query, server);
                     query_string += &suffix;
                     query_string
                 }
                 else {
-                    query.to_string() + "\n"
+                    self.query_object(query, server) + "\n"
                 }
             },
             _ => query.to_string() + "\n"
@@ -285,18 +500,7 @@ impl WhoisDatabase {
                         let result = self.map_domain_servers.get(&name);
                         if result.is_some() {
                             let server_name = result.unwrap();
-                            let q = (query.get_type(), server_name.clone());
-                            let server_query = self.map_server_query.get(&q);
-                            if server_query.is_some() {
-                                let &(ref prefix, ref suffix) = server_query.unwrap();
-                                let mut query_string = prefix.clone();
-                                query_string += &query.to_string();
-                                query_string += &suffix;
-                                return (Some(server_name.clone()), query_string);
-                            }
-                            else {
-                                return (Some(server_name.clone()), query.to_string() + "\n");
-                            }
+                            return (Some(server_name.clone()), self.get_query(query, server_name));
                         }
                     }
                 }
@@ -322,27 +526,126 @@ impl WhoisDatabase {
                     return (Some(server_name.clone()), query.to_string() + "\n");
                 }
             },
+            WhoisQuery::IpAddr(ref ip) => {
+                let server_name = self.ip_map.find(ip);
+                let query_string = self.get_query(query, &server_name);
+                (Some(server_name), query_string)
+            },
+            WhoisQuery::Network(ref network) => {
+                let server_name = self.ip_map.find_block(&network.first(), &network.last());
+                let query_string = self.get_query(query, &server_name);
+                (Some(server_name), query_string)
+            },
+            WhoisQuery::Range(ref range) => {
+                let server_name = self.ip_map.find_block(&range.first, &range.last);
+                let query_string = self.get_query(query, &server_name);
+                (Some(server_name), query_string)
+            },
             // TODO: Implement other types
             _ => (None, query.to_string() + "\n")
         }
     }
 
-    pub fn get_server_ip(&self, try: usize, server: Option<&String>) -> Option<IpAddr> {
-        if server.is_none() {
-            None
-        } else{
-            let server_str = server.unwrap();
-            let ips = self.map_server_ips.get(server_str);
-            if ips.is_some() {
-                let ips = ips.unwrap();
-                if ips.len() > 0 {
-                    return Some(ips[try % ips.len()]);
-                } else {
-                    return None;
-                }
+    // Bundled addresses of a server, None if it is not listed
+    pub fn get_server_addresses(&self, server: Option<&String>) -> Option<Addresses> {
+        let ips = match server.and_then(|name| self.map_server_ips.get(name)) {
+            Some(ips) if !ips.is_empty() => ips,
+            _ => return None
+        };
+        let mut addresses = Addresses::default();
+        for ip in ips.iter() {
+            match *ip {
+                IpAddr::V4(_) => addresses.ipv4.push(*ip),
+                IpAddr::V6(_) => addresses.ipv6.push(*ip)
             }
-            None
         }
+        Some(addresses)
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        IpAddr::from_str(s).unwrap()
+    }
+
+    fn map() -> IpMap {
+        // Listed out of order, more specific blocks before the blocks containing them
+        IpMap::parse("# Comment\n\
+                      10.1.2.0/24 c\n\
+                      10.2.0.0/16 d\n\
+                      10.1.0.0/16 b\n\
+                      \n\
+                      10.0.0.0/8  a\n\
+                      2001:db8::/32 e\n".lines())
+    }
+
+    fn database() -> WhoisDatabase {
+        WhoisDatabase::new(&IpConfig {
+            supported_versions: IP_V4 | IP_V6,
+            default_version: IP_V4
+        })
+    }
+
+    #[test]
+    fn ip_map_longest_prefix() {
+        let map = map();
+        assert_eq!(map.find(&ip("10.1.2.3")), "c");
+        assert_eq!(map.find(&ip("10.1.3.1")), "b");
+        assert_eq!(map.find(&ip("10.1.0.0")), "b");
+        assert_eq!(map.find(&ip("10.0.0.0")), "a");
+        assert_eq!(map.find(&ip("10.3.0.1")), "a");
+        assert_eq!(map.find(&ip("10.2.255.255")), "d");
+        assert_eq!(map.find(&ip("10.255.255.255")), "a");
+        assert_eq!(map.find(&ip("2001:db8::1")), "e");
+    }
+
+    #[test]
+    fn ip_map_block() {
+        let map = map();
+        assert_eq!(map.find_block(&ip("10.1.2.0"), &ip("10.1.2.255")), "c");
+        assert_eq!(map.find_block(&ip("10.1.2.0"), &ip("10.1.3.255")), "b");
+        assert_eq!(map.find_block(&ip("10.0.0.0"), &ip("10.255.255.255")), "a");
+        assert_eq!(map.find_block(&ip("10.1.0.0"), &ip("10.2.255.255")), "a");
+        assert_eq!(map.find_block(&ip("9.0.0.0"), &ip("10.0.0.1")), SERVER_ARIN);
+    }
+
+    #[test]
+    fn ip_map_arin_fallback() {
+        let map = map();
+        assert_eq!(map.find(&ip("9.255.255.255")), SERVER_ARIN);
+        assert_eq!(map.find(&ip("11.0.0.0")), SERVER_ARIN);
+        assert_eq!(map.find(&ip("0.0.0.0")), SERVER_ARIN);
+        assert_eq!(map.find(&ip("::1")), SERVER_ARIN);
+        assert_eq!(map.find(&ip("2001:db9::")), SERVER_ARIN);
+        assert_eq!(IpMap::parse("".lines()).find(&ip("10.0.0.1")), SERVER_ARIN);
+    }
+
+    #[test]
+    fn bundled_ip_routing() {
+        let db = database();
+        let server = |query: &str| db.get_server(&WhoisQuery::new(String::from(query), false));
+        assert_eq!(server("193.0.0.1"), (Some(String::from("whois.ripe.net")), String::from("-V Md5.2 193.0.0.1")));
+        assert_eq!(server("8.8.8.8"), (Some(String::from(SERVER_ARIN)), String::from("n + 8.8.8.8")));
+        assert_eq!(server("1.1.1.1").0, Some(String::from("whois.apnic.net")));
+        assert_eq!(server("2001:600::1").0, Some(String::from("whois.ripe.net")));
+        assert_eq!(server("2600::1").0, Some(String::from(SERVER_ARIN)));
+        // Reserved space is not listed
+        assert_eq!(server("127.0.0.1").0, Some(String::from(SERVER_ARIN)));
+        assert_eq!(server("10.0.0.1").0, Some(String::from(SERVER_ARIN)));
+    }
+
+    #[test]
+    fn bundled_network_routing() {
+        let db = database();
+        let server = |query: &str| db.get_server(&WhoisQuery::new(String::from(query), false));
+        assert_eq!(server("193.0.0.0/16"), (Some(String::from("whois.ripe.net")), String::from("-V Md5.2 193.0.0.0/16")));
+        assert_eq!(server("200.1.2.3/8").0, Some(String::from("whois.lacnic.net")));
+        assert_eq!(server("41.0.0.1-41.0.0.5").0, Some(String::from("whois.afrinic.net")));
+        // Blocks spanning several registries are looked up at ARIN
+        assert_eq!(server("1.0.0.0/7").0, Some(String::from(SERVER_ARIN)));
+    }
+}
@@ -12,21 +12,43 @@ pub trait WhoisHandler {
     fn handle(&mut self, client: &mut WhoisClient);
 }
 
+// The header is followed by one record per query, integers are little-endian:
+// query length (u64), query (A-label for domains), U-label length (u64), U-label (empty unless an IDN),
+// outcome (u8), response length (u64), response
+static BINARY_MAGIC: &'static [u8] = b"MASSWHOIS";
+static BINARY_VERSION: u8 = 2; // Version 1 had neither header, U-label nor outcome
+
 pub struct WhoisOutputBinary {
-    pub writer: Box<Write>
+    writer: Box<Write>
+}
+
+impl WhoisOutputBinary {
+    // Starts the output with the header, which identifies the format version
+    pub fn new(mut writer: Box<Write>) -> Self {
+        writer.write(BINARY_MAGIC).expect("Write failure");
+        writer.write(&[BINARY_VERSION]).expect("Write failure");
+        WhoisOutputBinary {
+            writer: writer
+        }
+    }
+
+    // Length-prefixed field of a record
+    fn write_field(&mut self, data: &[u8]) {
+        let mut buf: [u8; 8] = [0; 8];
+        byteorder::LittleEndian::write_u64(&mut buf, data.len() as u64);
+        self.writer.write(&buf).expect("Write failure");
+        self.writer.write(data).expect("Write failure");
+    }
 }
 
 impl WhoisHandler for WhoisOutputBinary {
     fn handle(&mut self, client: &mut WhoisClient) {
         let query_str = client.query.to_string();
-        let query_bytes = query_str.as_bytes();
-        let mut buf: [u8; 8] = [0; 8];
-        byteorder::LittleEndian::write_u64(&mut buf, query_bytes.len() as u64);
-        self.writer.write(&buf).expect("Write failure");
-        self.writer.write(query_bytes).expect("Write failure");
-        byteorder::LittleEndian::write_u64(&mut buf, client.inbuf.len() as u64);
-        self.writer.write(&buf).expect("Write failure");
-        self.writer.write(client.inbuf.as_ref()).expect("Write failure");
+        self.write_field(query_str.as_bytes());
+        let unicode = client.query.unicode().unwrap_or_default();
+        self.write_field(unicode.as_bytes());
+        self.writer.write(&[client.outcome.code()]).expect("Write failure");
+        self.write_field(client.inbuf.as_ref());
     }
 }
 
@@ -37,7 +59,17 @@ pub struct WhoisOutputAvailability {
 impl WhoisHandler for WhoisOutputAvailability {
     fn handle(&mut self, client: &mut WhoisClient) {
         let availability = client.availability.to_string();
-        let line: String = client.query.to_string() + " " + &availability + "\n";
+        let mut line: String = client.query.display() + " " + &availability;
+        if client.outcome != Outcome::Complete {
+            line = line + " " + &client.outcome.to_string();
+        }
+        if let Some(strategy) = client.discovered_by {
+            line = line + " DISCOVERED_VIA_" + &strategy.to_string();
+        }
+        if let Some(stage) = client.decided_by {
+            line = line + " DECIDED_BY_" + &stage.to_string();
+        }
+        line.push('\n');
         self.writer.write(line.as_bytes()).expect("Write failure");
     }
 }
@@ -49,7 +81,17 @@ pub struct WhoisOutputReadable {
 impl WhoisHandler for WhoisOutputReadable {
     fn handle(&mut self, client: &mut WhoisClient) {
         self.writer.write("----- ".as_bytes()).expect("Write failure");
-        self.writer.write(client.query.to_string().as_bytes()).expect("Write failure");
+        self.writer.write(client.query.display().as_bytes()).expect("Write failure");
+        if client.outcome != Outcome::Complete {
+            self.writer.write(" (".as_bytes()).expect("Write failure");
+            self.writer.write(client.outcome.to_string().as_bytes()).expect("Write failure");
+            self.writer.write(")".as_bytes()).expect("Write failure");
+        }
+        if let Some(strategy) = client.discovered_by {
+            self.writer.write(" (DISCOVERED_VIA_".as_bytes()).expect("Write failure");
+            self.writer.write(strategy.to_string().as_bytes()).expect("Write failure");
+            self.writer.write(")".as_bytes()).expect("Write failure");
+        }
         self.writer.write(" -----\n\n".as_bytes()).expect("Write failure");
         self.writer.write(client.inbuf.as_ref()).expect("Write failure");
         self.writer.write("\n\n".as_bytes()).expect("Write failure");
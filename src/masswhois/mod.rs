@@ -2,38 +2,136 @@ pub mod query;
 pub mod database;
 pub mod handler;
 pub mod client;
+pub mod ratelimit;
 
-use mio::{Token, Poll, Ready, PollOpt, Events};
-use mio::tcp::TcpStream;
-use std::net::IpAddr;
-use std::collections::LinkedList;
+use mio::{Token, Poll, Ready, Events};
+use std::net::{IpAddr, SocketAddr};
+use std::collections::{HashMap, LinkedList, VecDeque};
 use mio::unix::UnixReady;
 use masswhois::query::*;
 use masswhois::database::*;
 use masswhois::client::*;
 use masswhois::handler::*;
+use masswhois::ratelimit::*;
 use dnsutils::*;
-use std::net::Ipv4Addr;
+pub use dnsutils::{IpConfig, IpVersion, IP_V4, IP_V6};
+use dnsutils::expiry::{ExpiryHandler, ExpiryHandle};
+use std::time::{Duration, Instant};
+use std::cmp::min;
+use std::str::FromStr;
 
-bitflags! {
-    pub struct IpVersion: u8 {
-        const IP_V4 = 1;
-        const IP_V6 = 2;
-    }
+#[derive(Copy, Clone)]
+pub struct Timeouts {
+    pub connect: Duration, // Until the TCP handshake has completed
+    pub idle: Duration, // Without receiving any data
+    pub total: Duration // For the whole query
 }
 
 #[derive(Copy, Clone)]
-pub struct IpConfig {
-    pub default_version: IpVersion,
-    pub supported_versions: IpVersion
+pub struct RetryPolicy {
+    pub attempts: usize, // Maximum number of attempts per query, including the first one
+    pub backoff: Duration, // Delay before the first retry, doubled for every further one
+    pub rotate: bool // Move on to the next address of the server when retrying
+}
+
+impl RetryPolicy {
+    // Delay before the retry following the given (zero-based) failed attempt
+    pub fn delay(&self, attempt: usize) -> Duration {
+        self.backoff * (1u32 << min(attempt, MAX_BACKOFF_DOUBLINGS))
+    }
+}
+
+// A query which is ready to be sent but held back by the limits of its server
+struct PendingQuery {
+    query: WhoisQuery,
+    query_str: String,
+    address: Option<IpAddr>,
+    port: u16,
+    alternatives: Vec<IpAddr>, // Further addresses of the server to race against the first one
+    server: Option<String>,
+    tries: usize,
+    discovery: bool
+}
+
+// Keys under which per-server limits are accounted, the server name first if it is known
+fn limit_keys(server: &Option<String>, address: Option<IpAddr>) -> Vec<String> {
+    let mut keys = Vec::with_capacity(2);
+    if let Some(ref name) = *server {
+        keys.push(name.clone());
+    }
+    if let Some(ip) = address {
+        keys.push(ip.to_string());
+    }
+    keys
+}
+
+// The address to connect to first and the others to race against it. Retries start with the next address.
+fn pick_address(addresses: &Addresses, ip_config: &IpConfig, tries: usize) -> (IpAddr, Vec<IpAddr>) {
+    let mut ordered = addresses.ordered(ip_config);
+    let first = tries % ordered.len();
+    let mut rotated = ordered.split_off(first);
+    rotated.extend(ordered);
+    let address = rotated.remove(0);
+    (address, rotated)
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum DiscoveryStrategy {
+    IANA, // Ask whois.iana.org for the TLD
+    DNS // Use the CNAME target of <tld>.whois-servers.net
+}
+
+impl ToString for DiscoveryStrategy {
+    fn to_string(&self) -> String {
+        match *self {
+            DiscoveryStrategy::IANA => String::from("IANA"),
+            DiscoveryStrategy::DNS => String::from("DNS")
+        }
+    }
+}
+
+impl FromStr for DiscoveryStrategy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "iana" => Ok(DiscoveryStrategy::IANA),
+            "dns" => Ok(DiscoveryStrategy::DNS),
+            _ => Err(())
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum AddressSource {
+    Bundled, // Use the addresses of server_ip.txt, resolve servers which are not listed
+    DNS // Always resolve server names
+}
+
+impl FromStr for AddressSource {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "bundled" => Ok(AddressSource::Bundled),
+            "dns" => Ok(AddressSource::DNS),
+            _ => Err(())
+        }
+    }
 }
 
 #[derive(PartialEq, Eq, Copy, Clone, Debug)]
 pub enum Status {
-    Initial, DNS, Referral, Other
+    Initial, DNS, Referral, Retry, Discovery, Other
 }
 
-pub struct MassWhois<'a> {
+static TIMER_RESOLUTION_MS: u64 = 10;
+static MAX_POLL_TIMEOUT_SECS: u64 = 1;
+static WAKEUP_INTERVAL_SECS: u64 = 1; // Until held back queries are checked again
+static WHOIS_SERVERS_SUFFIX: &'static str = ".whois-servers.net";
+static MAX_BACKOFF_DOUBLINGS: usize = 8;
+
+pub struct MassWhois {
     concurrency: usize, // Number of concurrent TCP connections
     servers: Vec<IpAddr>,
     running: usize,
@@ -47,19 +145,35 @@ pub struct MassWhois<'a> {
     infer_servers: bool,
     pub ip_config: IpConfig,
     output: Box<WhoisHandler>,
-    resolver: CachingResolver<'a, usize>,
+    pub resolver: CachingResolver<usize>,
     infer: bool,
     resolving_names: Vec<String>,
-    availability_check: bool
+    availability_check: bool,
+    dns_precheck: bool, // Domains with NS records are unavailable, only the others are checked through whois
+    timeouts: Timeouts,
+    retry: RetryPolicy,
+    timers: ExpiryHandler<(usize, usize)>, // (client index, client serial)
+    serial: usize,
+    rate_limiter: RateLimiter,
+    concurrency_limiter: ConcurrencyLimiter,
+    pending: VecDeque<PendingQuery>,
+    idle: Vec<usize>, // Slots waiting for work
+    next_wakeup: Option<(Instant, ExpiryHandle)>, // When idle slots are woken up next, and its timer
+    address_source: AddressSource,
+    host_overrides: HashMap<String, SocketAddr>, // Fixed addresses of whois servers by name, checked before DNS
+    discovery: Vec<DiscoveryStrategy>, // Server discovery strategies for unknown TLDs, in order of preference
+    discovery_progress: HashMap<String, usize>, // Number of discovery strategies tried by TLD
+    awaiting_discovery: HashMap<String, Vec<String>>, // Queries by the TLD whose server is being discovered
+    requeued: VecDeque<String> // Queries to be processed again before reading further input
 }
 
-impl<'a> MassWhois<'a> {
+impl MassWhois {
 
-    pub fn new(concurrency: usize, ip_config: IpConfig, infer_servers: bool, next_query: Box<WhoisRawQuerySupplier>, output: Box<WhoisHandler>, infer: bool, availability_check: bool) -> Self {
+    pub fn new(concurrency: usize, ip_config: IpConfig, servers: Vec<IpAddr>, resolvers: Vec<SocketAddr>, dns_tcp: bool, edns_payload: Option<u16>, infer_servers: bool, address_source: AddressSource, host_overrides: HashMap<String, SocketAddr>, discovery: Vec<DiscoveryStrategy>, next_query: Box<WhoisRawQuerySupplier>, output: Box<WhoisHandler>, infer: bool, availability_check: bool, dns_precheck: bool, timeouts: Timeouts, retry: RetryPolicy, rate_limits: RateLimits, concurrency_limits: ConcurrencyLimits) -> Self {
         let poll = Poll::new().expect("Failed to create polling interface.");
         let mut result = Self {
             concurrency: concurrency,
-            servers: Default::default(),
+            servers: servers,
             clients: Vec::with_capacity(concurrency),
             end_reached: Default::default(),
             poll: poll,
@@ -70,79 +184,143 @@ impl<'a> MassWhois<'a> {
             infer_servers: infer_servers,
             ip_config: ip_config,
             output: output,
-            resolver: CachingResolver::from_config(ip_config, 1000, 10000, 24 * 60, 60),
+            resolver: CachingResolver::from_config(ip_config, 1000, 10000),
             infer: infer,
             resolving_names: Vec::with_capacity(concurrency),
-            availability_check: availability_check
+            availability_check: availability_check,
+            dns_precheck: dns_precheck,
+            timeouts: timeouts,
+            retry: retry,
+            timers: ExpiryHandler::new(Duration::from_millis(TIMER_RESOLUTION_MS)),
+            serial: 0,
+            rate_limiter: RateLimiter::new(rate_limits),
+            concurrency_limiter: ConcurrencyLimiter::new(concurrency_limits),
+            pending: VecDeque::new(),
+            idle: Vec::with_capacity(concurrency),
+            next_wakeup: None,
+            address_source: address_source,
+            host_overrides: host_overrides,
+            discovery: discovery,
+            discovery_progress: Default::default(),
+            awaiting_discovery: Default::default(),
+            requeued: VecDeque::new()
         };
         for i in 0..concurrency {
             result.resolving_names.push(String::from(""));
-            result.clients.push(WhoisClient::new(i,
-                                                 WhoisQuery::Unspecified(String::from("")),
-                                                 String::from(""),
-                                                 Some(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))),
-                                                 Some(String::from(""))));
+            let mut placeholder = WhoisClient::new(i,
+                                                   WhoisQuery::Unspecified(String::from("")),
+                                                   String::from(""),
+                                                   None,
+                                                   WHOIS_PORT,
+                                                   Some(String::from("")));
+            placeholder.terminated = true;
+            result.clients.push(placeholder);
+        }
+        if !resolvers.is_empty() {
+            result.resolver.set_servers(resolvers);
         }
+        result.resolver.set_force_tcp(dns_tcp);
+        result.resolver.set_edns_payload(edns_payload);
         result.resolver.add_to_poll(&mut result.poll, concurrency);
         result
     }
 
     pub fn start(&mut self) {
         for i in 0..self.concurrency {
-            if self.end_reached {
-                break;
-            }
             self.next_client(i, Status::Initial);
         }
         self.handle_events();
     }
 
     fn check_termination(&mut self) -> bool {
-        self.end_reached && self.running <= 0
+        self.end_reached && self.running <= 0 && self.pending.is_empty() && self.requeued.is_empty()
     }
 
     fn handle_events(&mut self) {
         loop {
-            self.poll.poll(&mut self.events, None).expect("Failed to poll.");
+            self.resolver.add_to_poll(&mut self.poll, self.concurrency);
+            // Wake up for the next timer, but at least once in a while
+            let mut poll_timeout = Duration::from_secs(MAX_POLL_TIMEOUT_SECS);
+            for next in self.timers.next_expiry().iter().chain(self.resolver.next_expiry().iter()) {
+                poll_timeout = min(poll_timeout, *next);
+            }
+            self.poll.poll(&mut self.events, Some(poll_timeout)).expect("Failed to poll.");
             let mut terminated_clients: Vec<(usize, Status)> = Default::default();
+            let mut finished_clients: Vec<usize> = Default::default();
+            let mut retried_clients: Vec<usize> = Default::default();
+            let mut wakeup = false;
             let mut resolved : LinkedList<usize> = LinkedList::new();
+            let mut abandoned: Vec<usize> = Default::default(); // Clients whose connection race was decided
             for event in self.events.iter() {
                 match event.token() {
                     Token(i) => {
                         if i < self.concurrency {
                             let ref mut client : WhoisClient = self.clients[i];
-                            if event.readiness().is_readable() {
-                                {
-                                    let ref mut stream = client.stream;
-                                    client.inbuf.read_from::<TcpStream>(stream).expect("Failed to read.");
+                            if client.terminated {
+                                continue;
+                            }
+                            let mut readiness = event.readiness();
+                            if abandoned.contains(&i) {
+                                // The event might stem from an attempt which has lost the race
+                                readiness = readiness & (Ready::readable() | Ready::writable());
+                            }
+                            let mut finished = false;
+                            if !client.connected {
+                                let address = client.address;
+                                let raced = !client.racing.is_empty();
+                                let error = client.settle(&self.poll, Instant::now());
+                                if client.address != address {
+                                    // The slot is accounted to the address of the stream, which is released later
+                                    self.concurrency_limiter.release(&client.acquired);
+                                    client.acquired = limit_keys(&client.server, client.address);
+                                    self.concurrency_limiter.acquire(&client.acquired);
                                 }
-                                if UnixReady::from(event.readiness()).is_hup() {
-                                    if self.availability_check {
-                                        client.availability = self.db.availability(client);
-                                    }
-                                    self.output.handle(client);
-                                    if !client.terminated {
-                                        client.terminated = true;
-                                        let ref_server = self.db.get_referral_server(client);
-                                        let status = if ref_server.is_some() && !self.availability_check {
-                                            client.server = ref_server;
-                                            client.status = Status::Referral;
-                                            Status::Referral
-                                        } else {
-                                            Status::Other
-                                        };
-
-                                        // TODO: Find way to call self.next_client here directly
-                                        // Maybe use #inline macro?
-                                        terminated_clients.push((i, status));
+                                if let Some(e) = error {
+                                    client.outcome = Outcome::from_error(&e);
+                                    finished_clients.push(i);
+                                    continue;
+                                }
+                                if !client.connected {
+                                    // None of the attempts has completed its handshake yet
+                                    continue;
+                                }
+                                if raced {
+                                    abandoned.push(i);
+                                    readiness = (readiness & Ready::readable()) | Ready::writable();
+                                }
+                            }
+                            if UnixReady::from(readiness).is_error() {
+                                if let Some(e) = client.take_error() {
+                                    client.outcome = Outcome::from_error(&e);
+                                    finished = true;
+                                }
+                            }
+                            if !finished && readiness.is_writable() {
+                                client.connected = true;
+                                if let Err(e) = client.send() {
+                                    client.outcome = Outcome::from_error(&e);
+                                    finished = true;
+                                }
+                            }
+                            if !finished && (readiness.is_readable() || UnixReady::from(readiness).is_hup()) {
+                                match client.receive() {
+                                    Ok(eof) => {
+                                        client.connected = true;
+                                        client.last_activity = Instant::now();
+                                        finished = eof || UnixReady::from(readiness).is_hup();
+                                    },
+                                    Err(e) => {
+                                        client.outcome = Outcome::from_error(&e);
+                                        finished = true;
                                     }
                                 }
-                            } else if event.readiness().is_writable() {
-                                let ref mut stream = client.stream;
-                                client.outbuf.write_to::<TcpStream>(stream).expect("Failed to write.");
+                            }
+                            if finished {
+                                finished_clients.push(i);
                             }
                         } else { // DNS response
-                            let mut handle = |tk: usize| {
+                            // The slots repeat their queries, which are answered from the cache then
+                            let mut handle = |tk: usize, _: Completion| {
                                 resolved.push_back(tk);
                             };
                             loop {
@@ -155,6 +333,30 @@ impl<'a> MassWhois<'a> {
                 }
             }
 
+            self.resolver.expire(&mut |tk: usize, _: Completion| {
+                resolved.push_back(tk);
+            });
+
+            self.expire_clients(&mut finished_clients, &mut retried_clients, &mut wakeup);
+
+            for i in finished_clients.iter() {
+                let status = self.finish_client(*i);
+                if status != Status::Retry {
+                    terminated_clients.push((*i, status));
+                }
+            }
+
+            for i in retried_clients.iter() {
+                self.next_client(*i, Status::Retry);
+            }
+
+            if wakeup {
+                self.next_wakeup = None;
+            }
+            if wakeup || (!finished_clients.is_empty() && (!self.pending.is_empty() || !self.requeued.is_empty())) {
+                self.wake_idle_clients();
+            }
+
             for c in terminated_clients.iter() {
                 self.next_client(c.0, c.1);
             }
@@ -169,66 +371,491 @@ impl<'a> MassWhois<'a> {
         }
     }
 
-    fn next_client(&mut self, i: usize, status: Status) {
-        if status != Status::Initial {
-            self.running = self.running - 1;
-        }
-        let orig_str = if status != Status::DNS && status != Status::Referral {
-            match self.next_query.get() {
-                None => {
-                    self.end_reached = true;
-                    return;
+    fn expire_clients(&mut self, finished_clients: &mut Vec<usize>, retried_clients: &mut Vec<usize>, wakeup: &mut bool) {
+        let mut expired: Vec<(usize, usize)> = Default::default();
+        self.timers.clean(&mut |e: &(usize, usize)| {
+            expired.push(*e);
+        });
+        let now = Instant::now();
+        for &(i, serial) in expired.iter() {
+            if i == self.concurrency {
+                *wakeup = true;
+                continue;
+            }
+            let ref mut client : WhoisClient = self.clients[i];
+            if client.serial != serial || finished_clients.contains(&i) {
+                continue;
+            }
+            if client.terminated {
+                // The backoff of a failed attempt is over
+                if client.status == Status::Retry {
+                    retried_clients.push(i);
+                }
+                continue;
+            }
+            match client.timeout(&self.timeouts, now) {
+                Some(outcome) => {
+                    client.outcome = outcome;
+                    finished_clients.push(i);
                 },
-                Some(s) => s
+                None => {
+                    if !client.connected && !client.alternatives.is_empty() && now >= client.next_attempt {
+                        // No handshake has completed in time, race the next address
+                        client.attempt(&self.poll, now);
+                    }
+                    // Check again when the next deadline is due
+                    self.timers.add((i, serial), client.remaining(&self.timeouts, now));
+                }
+            }
+        }
+    }
+
+    // Report a client whose connection has ended and decide how its slot continues
+    fn finish_client(&mut self, i: usize) -> Status {
+        {
+            let ref mut client : WhoisClient = self.clients[i];
+            if client.stream.is_some() {
+                client.deregister(&self.poll);
             }
+            self.concurrency_limiter.release(&client.acquired);
+            client.acquired.clear();
+            client.terminated = true;
+            if client.outcome == Outcome::Complete && client.inbuf.len() == 0 {
+                client.outcome = Outcome::Empty;
+            } else if client.outcome == Outcome::Complete && self.db.rate_limited(client) {
+                client.outcome = Outcome::RateLimited;
+            }
+        }
+        if self.clients[i].outcome == Outcome::RateLimited && self.requeue(i) {
+            return Status::Other;
+        }
+        if self.schedule_retry(i) {
+            return Status::Retry;
+        }
+        if self.clients[i].discovery {
+            self.finish_discovery(i);
+            return Status::Other;
+        }
+        let ref mut client : WhoisClient = self.clients[i];
+        if self.availability_check && client.outcome == Outcome::Complete {
+            client.availability = self.db.availability(client);
+            if self.dns_precheck {
+                client.decided_by = Some(Stage::Whois);
+            }
+        }
+        client.discovered_by = self.db.discovery_strategy(&client.query);
+        self.output.handle(client);
+        if client.outcome != Outcome::Complete {
+            return Status::Other;
+        }
+        let ref_server = self.db.get_referral_server(client);
+        if ref_server.is_some() && !self.availability_check {
+            client.server = ref_server;
+            client.status = Status::Referral;
+            Status::Referral
         } else {
-            self.resolving_names[i].clone()
+            Status::Other
+        }
+    }
+
+    // Pause the server which has rate limited the client and hold the query back until the cool-down is over
+    fn requeue(&mut self, i: usize) -> bool {
+        let ref client : WhoisClient = self.clients[i];
+        let keys = limit_keys(&client.server, client.address);
+        if keys.is_empty() {
+            return false;
+        }
+        self.rate_limiter.pause(&keys[0]);
+        if client.dns_tries + 1 >= self.retry.attempts {
+            return false;
+        }
+        let pending = PendingQuery {
+            query: client.query.clone(),
+            query_str: client.query_str.clone(),
+            address: client.address,
+            port: client.port,
+            alternatives: Vec::new(),
+            server: client.server.clone(),
+            tries: client.dns_tries + 1,
+            discovery: client.discovery
         };
+        let cooldown = self.rate_limiter.cooldown();
+        self.hold_back(pending, Some(cooldown));
+        true
+    }
 
-        let query = WhoisQuery::new(orig_str.clone(), !self.infer);
+    // Discover the whois server of an unknown TLD using the next strategy which has not been tried yet.
+    // Queries for the same TLD wait for a running discovery. Returns false if the server cannot be discovered.
+    fn start_discovery(&mut self, i: usize, domain: &String, orig_str: &String) -> bool {
+        let tld = String::from(domain.rsplit('.').next().unwrap());
+        if tld.is_empty() || self.db.undiscoverable_domains.contains(&tld) {
+            return false;
+        }
+        if let Some(queries) = self.awaiting_discovery.get_mut(&tld) {
+            queries.push(orig_str.clone());
+            return true;
+        }
+        let tried = *self.discovery_progress.get(&tld).unwrap_or(&0);
+        if tried >= self.discovery.len() {
+            self.db.undiscoverable_domains.insert(tld);
+            return false;
+        }
+        self.discovery_progress.insert(tld.clone(), tried + 1);
+        match self.discovery[tried] {
+            DiscoveryStrategy::IANA => {
+                self.awaiting_discovery.insert(tld.clone(), vec![orig_str.clone()]);
+                let query = WhoisQuery::Domain(tld);
+                let server = String::from(SERVER_IANA);
+                let ref mut client : WhoisClient = self.clients[i];
+                client.query_str = self.db.get_query(&query, &server);
+                client.query = query;
+                client.server = Some(server);
+                client.status = Status::Discovery;
+            },
+            DiscoveryStrategy::DNS => {
+                // The alias is replaced by its CNAME target once it has been resolved
+                let alias = tld.clone() + WHOIS_SERVERS_SUFFIX;
+                self.db.add_discovered_server(tld, alias, DiscoveryStrategy::DNS);
+                self.requeued.push_back(orig_str.clone());
+            }
+        }
+        true
+    }
 
-        let mut server = None;
-        let (server_name, query_str) = if status != Status::Referral && self.clients[i].status != Status::Referral {
-            self.db.get_server(&query)
+    // Process the response of IANA. Queries for the TLD continue with the next strategy if there is no server.
+    fn finish_discovery(&mut self, i: usize) {
+        let tld = self.clients[i].query.to_string();
+        let server = if self.clients[i].outcome == Outcome::Complete {
+            self.db.discovered_server(&self.clients[i])
         } else {
-            let whois_server = self.clients[i].server.clone().unwrap();
-            (self.clients[i].server.clone(), self.db.get_query(&query, &whois_server))
+            None
         };
+        if let Some(server) = server {
+            self.db.add_discovered_server(tld.clone(), server, DiscoveryStrategy::IANA);
+        }
+        if let Some(queries) = self.awaiting_discovery.remove(&tld) {
+            self.requeued.extend(queries);
+        }
+    }
+
+    // Keep the slot of a client which failed transiently and retry its query once the backoff is over
+    fn schedule_retry(&mut self, i: usize) -> bool {
+        let ref mut client : WhoisClient = self.clients[i];
+        if !client.outcome.is_transient() || client.dns_tries + 1 >= self.retry.attempts {
+            return false;
+        }
+        client.status = Status::Retry;
+        self.serial = self.serial.wrapping_add(1);
+        client.serial = self.serial;
+        self.timers.add((i, client.serial), self.retry.delay(client.dns_tries));
+        true
+    }
 
-        self.running = self.running + 1;
-        if server_name.is_some() {
-            match self.resolver.query(String::from(server_name.clone().unwrap()), i, status == Status::DNS) {
-                ResolvePromise::Resolving => {
-                    self.resolving_names[i] = orig_str;
+    // Check whether a query may be sent to its server now. Spends rate limit budget if so.
+    // Otherwise returns the time until the rate limit allows it, if the rate limit is the reason.
+    fn admit(&mut self, keys: &[String]) -> Result<(), Option<Duration>> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+        if !self.concurrency_limiter.has_capacity(keys) {
+            return Err(None);
+        }
+        self.rate_limiter.acquire(&keys[0]).map_err(|wait| Some(wait))
+    }
+
+    // Hand out a held back query whose server has capacity and budget again
+    fn take_pending(&mut self) -> Option<PendingQuery> {
+        for index in 0..self.pending.len() {
+            let keys = limit_keys(&self.pending[index].server, self.pending[index].address);
+            if self.admit(&keys).is_ok() {
+                return self.pending.remove(index);
+            }
+        }
+        None
+    }
+
+    fn hold_back(&mut self, pending: PendingQuery, wait: Option<Duration>) {
+        self.pending.push_back(pending);
+        if let Some(wait) = wait {
+            self.schedule_wakeup(wait);
+        }
+    }
+
+    // Wake up idle slots after the wait unless they are woken up earlier anyway
+    fn schedule_wakeup(&mut self, wait: Duration) {
+        let deadline = Instant::now() + wait;
+        if let Some((scheduled, handle)) = self.next_wakeup {
+            if scheduled <= deadline {
+                return;
+            }
+            self.timers.cancel(handle);
+        }
+        let wakeup_index = self.concurrency;
+        let handle = self.timers.add((wakeup_index, 0), wait);
+        self.next_wakeup = Some((deadline, handle));
+    }
+
+    // Slots which ran out of work continue with held back queries once a server has budget again
+    fn wake_idle_clients(&mut self) {
+        let idle: Vec<usize> = self.idle.drain(..).collect();
+        for i in idle {
+            self.next_client(i, Status::Initial);
+        }
+        if !self.pending.is_empty() {
+            self.schedule_wakeup(Duration::from_secs(WAKEUP_INTERVAL_SECS));
+        }
+    }
+
+    fn next_client(&mut self, i: usize, status: Status) {
+        let mut status = status;
+        loop {
+            if status != Status::Initial {
+                self.running = self.running - 1;
+            }
+            let retrying = self.clients[i].status == Status::Retry;
+            let discovering = self.clients[i].status == Status::Discovery;
+            let discovery = discovering || (retrying && self.clients[i].discovery);
+            let fresh = !retrying && !discovering && status != Status::DNS && status != Status::Referral;
+            if fresh {
+                if let Some(pending) = self.take_pending() {
+                    self.running = self.running + 1;
+                    if !self.connect(i, pending) {
+                        status = Status::Other;
+                        continue;
+                    }
                     return;
-                },
-                ResolvePromise::Resolved(_, None) => {
-                    // TODO: Handle properly
+                }
+                if self.requeued.is_empty() && (self.end_reached || self.pending.len() >= self.concurrency) {
+                    // Do not read further input while enough queries are held back
+                    self.idle.push(i);
                     return;
-                },
-                ResolvePromise::Resolved(_, Some(ip)) => {
-                    server = Some(ip);
-                    self.clients[i].status = Status::Initial;
                 }
             }
-        }
-        if !self.infer_servers || server.is_none() {
-            if self.servers.len() > 0 {
-                server = Some(self.servers[i % self.servers.len()])
+            let orig_str = if retrying || discovering || status == Status::Referral {
+                self.clients[i].query.to_string()
+            } else if fresh {
+                match self.requeued.pop_front().or_else(|| self.next_query.get()) {
+                    None => {
+                        self.end_reached = true;
+                        self.idle.push(i);
+                        return;
+                    },
+                    Some(s) => s
+                }
+            } else {
+                self.resolving_names[i].clone()
+            };
+
+            let query = if retrying || discovering {
+                self.clients[i].query.clone()
+            } else {
+                WhoisQuery::new(orig_str.clone(), !self.infer)
+            };
+            let tries = if retrying {
+                self.clients[i].dns_tries + 1
             } else {
-                server = None
+                0
+            };
+
+            if let WhoisQuery::Invalid(_) = query {
+                self.running = self.running + 1;
+                self.report_invalid(i, query);
+                status = Status::Other;
+                continue;
             }
-        };
 
-        let client: WhoisClient = WhoisClient::new(i, query, query_str, server, server_name);
-        let events = Ready::readable() | Ready::writable() | UnixReady::hup() | UnixReady::error();
-        self.poll.register(&client.stream, client.token, events, PollOpt::edge())
-            .expect("Failed to register poll.");
+            if self.availability_check && self.dns_precheck && !retrying && !discovering {
+                if let WhoisQuery::Domain(ref domain) = query {
+                    // The name is absolute, a search list expansion could find a delegation of another domain
+                    let mut absolute = domain.clone();
+                    if !absolute.ends_with(".") {
+                        absolute.push('.');
+                    }
+                    match self.resolver.lookup(absolute, RecordType::NS, i) {
+                        LookupPromise::Pending => {
+                            self.running = self.running + 1;
+                            self.resolving_names[i] = orig_str;
+                            return;
+                        },
+                        LookupPromise::Answered(_, Answer::Records(ref records)) if !records.is_empty() => {
+                            // A delegated domain is registered, the whois server is not needed
+                            self.running = self.running + 1;
+                            self.report_delegated(i, query.clone());
+                            status = Status::Other;
+                            continue;
+                        },
+                        // NXDOMAIN, no delegation or no usable response
+                        LookupPromise::Answered(..) => {}
+                    }
+                }
+            }
+
+            let mut server = None;
+            let mut port = WHOIS_PORT;
+            let mut alternatives = Vec::new();
+            let (mut server_name, mut query_str) = if retrying || discovering {
+                (self.clients[i].server.clone(), self.clients[i].query_str.clone())
+            } else if status != Status::Referral && self.clients[i].status != Status::Referral {
+                self.db.get_server(&query)
+            } else {
+                let whois_server = self.clients[i].server.clone().unwrap();
+                (self.clients[i].server.clone(), self.db.get_query(&query, &whois_server))
+            };
+
+            if server_name.is_none() && self.infer_servers && !self.discovery.is_empty() {
+                if let WhoisQuery::Domain(ref domain) = query {
+                    if self.start_discovery(i, domain, &orig_str) {
+                        // Either this slot now discovers the server or the query waits for another one to do so
+                        status = Status::Initial;
+                        continue;
+                    }
+                }
+            }
+
+            let host_override = server_name.as_ref().and_then(|name| self.host_overrides.get(name)).cloned();
+            let bundled = if self.address_source == AddressSource::Bundled {
+                self.db.get_server_addresses(server_name.as_ref())
+            } else {
+                None
+            };
+
+            self.running = self.running + 1;
+            if retrying && !self.retry.rotate && self.clients[i].address.is_some() {
+                server = self.clients[i].address;
+                port = self.clients[i].port;
+            } else if let Some(address) = host_override {
+                server = Some(address.ip());
+                port = address.port();
+                self.clients[i].status = Status::Initial;
+            } else if let Some(addresses) = bundled {
+                let (address, others) = pick_address(&addresses, &self.ip_config, tries);
+                server = Some(address);
+                alternatives = others;
+                self.clients[i].status = Status::Initial;
+            } else if server_name.is_some() {
+                match self.resolver.query(String::from(server_name.clone().unwrap()), i) {
+                    ResolvePromise::Resolving => {
+                        self.resolving_names[i] = orig_str;
+                        return;
+                    },
+                    ResolvePromise::Resolved(_, ref addresses) if addresses.is_empty()
+                            && self.db.drop_alias(server_name.as_ref().unwrap()) => {
+                        // The TLD has no whois-servers.net alias, continue with the next discovery strategy
+                        self.running = self.running - 1;
+                        self.clients[i].status = Status::Initial;
+                        self.requeued.push_back(orig_str);
+                        status = Status::Initial;
+                        continue;
+                    },
+                    ResolvePromise::Resolved(_, ref addresses) if addresses.is_empty() => {
+                        // Use the fallback servers if there are any, report the failure otherwise
+                        self.clients[i].status = Status::Initial;
+                    },
+                    ResolvePromise::Resolved(_, addresses) => {
+                        let (address, others) = pick_address(&addresses, &self.ip_config, tries);
+                        server = Some(address);
+                        alternatives = others;
+                        self.clients[i].status = Status::Initial;
+                        let alias = server_name.clone().unwrap();
+                        if let Some(canonical) = self.resolver.canonical_name(&alias) {
+                            if self.db.resolve_alias(&alias, &canonical) {
+                                query_str = self.db.get_query(&query, &canonical);
+                                server_name = Some(canonical);
+                            }
+                        }
+                    }
+                }
+            }
+            if !self.infer_servers || server.is_none() {
+                alternatives.clear();
+                port = WHOIS_PORT;
+                if self.servers.len() > 0 {
+                    server = Some(self.servers[(i + tries) % self.servers.len()])
+                } else {
+                    server = None
+                }
+            };
+
+            let pending = PendingQuery {
+                query: query,
+                query_str: query_str,
+                address: server,
+                port: port,
+                alternatives: alternatives,
+                server: server_name,
+                tries: tries,
+                discovery: discovery
+            };
+            if let Err(wait) = self.admit(&limit_keys(&pending.server, pending.address)) {
+                // The server is busy or has no budget left, continue with another query in the meantime
+                self.clients[i].status = Status::Initial;
+                self.hold_back(pending, wait);
+                status = Status::Other;
+                continue;
+            }
+            if !self.connect(i, pending) {
+                status = Status::Other;
+                continue;
+            }
+            return;
+        }
+    }
+
+    fn report_invalid(&mut self, i: usize, query: WhoisQuery) {
+        let mut client = WhoisClient::new(i, query, String::new(), None, WHOIS_PORT, None);
+        client.terminated = true;
+        client.outcome = Outcome::InvalidQuery;
+        self.clients[i] = client;
+        self.output.handle(&mut self.clients[i]);
+    }
+
+    fn report_delegated(&mut self, i: usize, query: WhoisQuery) {
+        let mut client = WhoisClient::new(i, query, String::new(), None, WHOIS_PORT, None);
+        client.terminated = true;
+        client.outcome = Outcome::Complete;
+        client.availability = Availability::UNAVAILABLE;
+        client.decided_by = Some(Stage::DNS);
+        self.clients[i] = client;
+        self.output.handle(&mut self.clients[i]);
+    }
+
+    // Start the connection for a query. Returns false if the slot is free again immediately.
+    fn connect(&mut self, i: usize, pending: PendingQuery) -> bool {
+        let mut client: WhoisClient = WhoisClient::new(i, pending.query, pending.query_str, pending.address, pending.port, pending.server);
+        client.dns_tries = pending.tries;
+        client.discovery = pending.discovery;
+        client.alternatives = pending.alternatives.into_iter().collect();
+        match client.stream {
+            Some(ref stream) => client.register(&self.poll, stream),
+            // Fail over to the next address right away
+            None => client.attempt(&self.poll, client.started)
+        }
+        if client.stream.is_none() {
+            // The connection could not even be initiated, report it and continue with the next query
+            client.terminated = true;
+            self.clients[i] = client;
+            if self.schedule_retry(i) {
+                return true;
+            }
+            if self.clients[i].discovery {
+                self.finish_discovery(i);
+                return false;
+            }
+            self.clients[i].discovered_by = self.db.discovery_strategy(&self.clients[i].query);
+            self.output.handle(&mut self.clients[i]);
+            return false;
+        }
+        client.acquired = limit_keys(&client.server, client.address);
+        self.concurrency_limiter.acquire(&client.acquired);
+        self.serial = self.serial.wrapping_add(1);
+        client.serial = self.serial;
+        self.timers.add((i, client.serial), client.remaining(&self.timeouts, client.started));
         self.clients[i] = client;
         let ref mut client = self.clients[i];
         let inbuf_len = client.inbuf.len();
         client.inbuf.consume(inbuf_len);
         let outbuf_len = client.inbuf.len();
         client.outbuf.consume(outbuf_len);
+        true
     }
 }
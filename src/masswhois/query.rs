@@ -1,11 +1,159 @@
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::str::FromStr;
+use dnsutils::idna;
+
+// Address block given by an address within it and the prefix length
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct IpNetwork {
+    pub address: IpAddr,
+    pub prefix: u8
+}
+
+impl IpNetwork {
+    // Clear or set all host bits of the address
+    fn mask(&self, set: bool) -> IpAddr {
+        match self.address {
+            IpAddr::V4(addr) => {
+                let mut octets = addr.octets();
+                IpNetwork::mask_octets(&mut octets, self.prefix, set);
+                IpAddr::V4(Ipv4Addr::from(octets))
+            },
+            IpAddr::V6(addr) => {
+                let mut octets = addr.octets();
+                IpNetwork::mask_octets(&mut octets, self.prefix, set);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+        }
+    }
+
+    fn mask_octets(octets: &mut [u8], prefix: u8, set: bool) {
+        for (i, octet) in octets.iter_mut().enumerate() {
+            let bit = (i * 8) as u8;
+            let host_mask: u8 = if bit >= prefix {
+                0xff
+            } else if prefix - bit < 8 {
+                0xff >> (prefix - bit)
+            } else {
+                0
+            };
+            if set {
+                *octet |= host_mask;
+            } else {
+                *octet &= !host_mask;
+            }
+        }
+    }
+
+    pub fn first(&self) -> IpAddr {
+        self.mask(false)
+    }
+
+    pub fn last(&self) -> IpAddr {
+        self.mask(true)
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        *ip >= self.first() && *ip <= self.last()
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = ();
+
+    // Parse networks in CIDR notation. Host bits are cleared.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.splitn(2, '/');
+        let address = IpAddr::from_str(fields.next().unwrap()).map_err(|_| ())?;
+        let prefix = fields.next().ok_or(())?.parse::<u8>().map_err(|_| ())?;
+        let max_prefix = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128
+        };
+        if prefix > max_prefix {
+            return Err(());
+        }
+        let network = IpNetwork {
+            address: address,
+            prefix: prefix
+        };
+        Ok(IpNetwork {
+            address: network.first(),
+            prefix: prefix
+        })
+    }
+}
+
+impl ToString for IpNetwork {
+    fn to_string(&self) -> String {
+        format!("{}/{}", self.address, self.prefix)
+    }
+}
+
+// Address block given by its first and last address
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct IpRange {
+    pub first: IpAddr,
+    pub last: IpAddr
+}
+
+impl IpRange {
+    // The network which spans exactly the range, if there is one
+    pub fn to_network(&self) -> Option<IpNetwork> {
+        let max_prefix = match self.first {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128
+        };
+        (0..max_prefix + 1).map(|prefix| IpNetwork {
+            address: self.first,
+            prefix: prefix
+        }).find(|network| network.first() == self.first && network.last() == self.last)
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = ();
+
+    // Parse ranges of the form FIRST-LAST, both of the same version and in ascending order
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.splitn(2, '-');
+        let first = IpAddr::from_str(fields.next().unwrap().trim()).map_err(|_| ())?;
+        let last = IpAddr::from_str(fields.next().ok_or(())?.trim()).map_err(|_| ())?;
+        if first.is_ipv4() != last.is_ipv4() || first > last {
+            return Err(());
+        }
+        Ok(IpRange {
+            first: first,
+            last: last
+        })
+    }
+}
+
+impl ToString for IpRange {
+    // Notation of inetnum objects (RPSL)
+    fn to_string(&self) -> String {
+        format!("{} - {}", self.first, self.last)
+    }
+}
+
+// Whether the input is meant as an address block, i.e. an address followed by a prefix length or another address
+fn is_address_block(s: &str) -> bool {
+    if let Some(pos) = s.find('/') {
+        return IpAddr::from_str(&s[..pos]).is_ok();
+    }
+    let mut fields = s.splitn(2, '-');
+    let first = fields.next().unwrap().trim();
+    match fields.next() {
+        Some(last) => IpAddr::from_str(first).is_ok() && IpAddr::from_str(last.trim()).is_ok(),
+        None => false
+    }
+}
 
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub enum WhoisQueryType {
     Domain = 1,
     IpAddr,
     AS,
+    Network,
     Unspecified
 }
 
@@ -14,7 +162,10 @@ pub enum WhoisQuery {
     Domain(String),
     IpAddr(IpAddr),
     AS(u32),
-    Unspecified(String)
+    Network(IpNetwork),
+    Range(IpRange), // IPv4 ranges which do not form a network, IPv6 blocks are only known by prefix
+    Unspecified(String),
+    Invalid(String) // Address blocks which cannot be queried
 }
 
 impl WhoisQuery {
@@ -23,19 +174,59 @@ impl WhoisQuery {
             WhoisQuery::Unspecified(query)
         } else {
             let ip = IpAddr::from_str(query.as_str());
+            let network = IpNetwork::from_str(query.as_str());
+            let range = IpRange::from_str(query.as_str());
             if ip.is_ok() {
                 WhoisQuery::IpAddr(ip.unwrap())
+            } else if network.is_ok() {
+                WhoisQuery::Network(network.unwrap())
+            } else if range.is_ok() {
+                let range = range.unwrap();
+                match range.to_network() {
+                    Some(network) => WhoisQuery::Network(network),
+                    None if range.first.is_ipv4() => WhoisQuery::Range(range),
+                    None => WhoisQuery::Invalid(query)
+                }
+            } else if is_address_block(query.as_str()) {
+                WhoisQuery::Invalid(query)
             } else {
                 let asn = query.parse::<u32>();
                 if asn.is_ok() {
                     WhoisQuery::AS(asn.unwrap())
                 } else {
-                    WhoisQuery::Domain(query)
+                    // Domains are routed and sent as A-labels
+                    match idna::to_ascii(query.as_str()) {
+                        Some(ascii) => WhoisQuery::Domain(ascii),
+                        None => WhoisQuery::Domain(query)
+                    }
                 }
             }
         }
     }
 
+    // U-label form of internationalized domain names
+    pub fn unicode(&self) -> Option<String> {
+        match *self {
+            WhoisQuery::Domain(ref x) => {
+                let unicode = idna::to_unicode(x);
+                if unicode != *x {
+                    Some(unicode)
+                } else {
+                    None
+                }
+            },
+            _ => None
+        }
+    }
+
+    // Query as shown in the output, with both U-label and A-label for internationalized domain names
+    pub fn display(&self) -> String {
+        match self.unicode() {
+            Some(unicode) => unicode + " (" + &self.to_string() + ")",
+            None => self.to_string()
+        }
+    }
+
     pub fn get_type(&self) -> WhoisQueryType {
         match *self {
             WhoisQuery::Domain(_) => {
@@ -47,7 +238,10 @@ impl WhoisQuery {
             WhoisQuery::AS(_) => {
                 WhoisQueryType::AS
             },
-            WhoisQuery::Unspecified(_) => {
+            WhoisQuery::Network(_) | WhoisQuery::Range(_) => {
+                WhoisQueryType::Network
+            },
+            WhoisQuery::Unspecified(_) | WhoisQuery::Invalid(_) => {
                 WhoisQueryType::Unspecified
             }
         }
@@ -66,9 +260,97 @@ impl ToString for WhoisQuery {
             WhoisQuery::AS(x) => {
                 x.to_string()
             },
-            WhoisQuery::Unspecified(ref x) => {
+            WhoisQuery::Network(ref x) => {
+                x.to_string()
+            },
+            WhoisQuery::Range(ref x) => {
+                x.to_string()
+            },
+            WhoisQuery::Unspecified(ref x) | WhoisQuery::Invalid(ref x) => {
                 x.clone()
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> WhoisQuery {
+        WhoisQuery::new(String::from(s), false)
+    }
+
+    fn parsed(s: &str) -> (WhoisQueryType, String) {
+        let query = parse(s);
+        (query.get_type(), query.to_string())
+    }
+
+    #[test]
+    fn addresses() {
+        assert!(parsed("192.0.2.1") == (WhoisQueryType::IpAddr, String::from("192.0.2.1")));
+        assert!(parsed("2001:db8::1") == (WhoisQueryType::IpAddr, String::from("2001:db8::1")));
+    }
+
+    #[test]
+    fn networks() {
+        assert!(parsed("192.0.2.0/24") == (WhoisQueryType::Network, String::from("192.0.2.0/24")));
+        // Host bits are cleared
+        assert!(parsed("192.0.2.77/24") == (WhoisQueryType::Network, String::from("192.0.2.0/24")));
+        assert!(parsed("2001:db8::1/32") == (WhoisQueryType::Network, String::from("2001:db8::/32")));
+        assert!(parsed("0.0.0.0/0") == (WhoisQueryType::Network, String::from("0.0.0.0/0")));
+        assert!(parsed("192.0.2.1/32") == (WhoisQueryType::Network, String::from("192.0.2.1/32")));
+    }
+
+    #[test]
+    fn network_bounds() {
+        let network = IpNetwork::from_str("10.1.2.3/12").unwrap();
+        assert!(network.first() == IpAddr::from_str("10.0.0.0").unwrap());
+        assert!(network.last() == IpAddr::from_str("10.15.255.255").unwrap());
+        assert!(network.contains(&IpAddr::from_str("10.8.0.1").unwrap()));
+        assert!(!network.contains(&IpAddr::from_str("10.16.0.0").unwrap()));
+    }
+
+    #[test]
+    fn ranges() {
+        // Ranges spanning a network are queried as such
+        assert!(parsed("192.0.2.0 - 192.0.2.255") == (WhoisQueryType::Network, String::from("192.0.2.0/24")));
+        assert!(parsed("2001:db8::-2001:db8::ffff") == (WhoisQueryType::Network, String::from("2001:db8::/112")));
+        match parse("192.0.2.1-192.0.2.9") {
+            WhoisQuery::Range(range) => assert_eq!(range.to_string(), "192.0.2.1 - 192.0.2.9"),
+            _ => panic!("Expected a range")
+        }
+    }
+
+    #[test]
+    fn invalid_blocks() {
+        for s in ["192.0.2.9-192.0.2.1", "192.0.2.1-2001:db8::1", "2001:db8::1-2001:db8::9",
+                  "192.0.2.1/33", "2001:db8::/129", "192.0.2.1/x"].iter() {
+            match parse(s) {
+                WhoisQuery::Invalid(ref x) => assert_eq!(x, s),
+                ref query => panic!("{} parsed as {}", s, query.to_string())
+            }
+        }
+    }
+
+    #[test]
+    fn other_types() {
+        assert!(parsed("3333") == (WhoisQueryType::AS, String::from("3333")));
+        assert!(parsed("example.com") == (WhoisQueryType::Domain, String::from("example.com")));
+        assert!(parsed("1.2.3.4-foo.de") == (WhoisQueryType::Domain, String::from("1.2.3.4-foo.de")));
+        match WhoisQuery::new(String::from("192.0.2.1"), true) {
+            WhoisQuery::Unspecified(ref x) => assert_eq!(x, "192.0.2.1"),
+            _ => panic!("Expected an unspecified query")
+        }
+    }
+
+    #[test]
+    fn idn_domains() {
+        let query = parse("Bücher.de");
+        assert!(query.get_type() == WhoisQueryType::Domain);
+        assert_eq!(query.to_string(), "xn--bcher-kva.de");
+        assert_eq!(query.unicode(), Some(String::from("bücher.de")));
+        assert_eq!(query.display(), "bücher.de (xn--bcher-kva.de)");
+        assert_eq!(parse("example.com").unicode(), None);
+    }
+}
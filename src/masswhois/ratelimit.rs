@@ -0,0 +1,298 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use dnsutils::expiry::{ExpiryHandler, ExpiryHandle};
+
+static COOLDOWN_TIMER_RESOLUTION_MS: u64 = 100;
+
+#[derive(Copy, Clone)]
+pub struct Rate {
+    pub per_second: f64, // Sustained number of queries per second
+    pub burst: f64 // Number of queries which may be sent at once
+}
+
+impl FromStr for Rate {
+    type Err = ();
+
+    // Parse rates of the form QPS or QPS:BURST
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.splitn(2, ':');
+        let per_second = fields.next().unwrap().parse::<f64>().map_err(|_| ())?;
+        let burst = match fields.next() {
+            Some(b) => b.parse::<f64>().map_err(|_| ())?,
+            None => per_second.ceil()
+        };
+        if !(per_second > 0.0) || !(burst >= 1.0) {
+            return Err(());
+        }
+        Ok(Rate {
+            per_second: per_second,
+            burst: burst
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimits {
+    pub default: Option<Rate>, // Applies to servers without their own rate, unlimited if none
+    pub servers: HashMap<String, Rate>,
+    pub cooldown: Duration // Pause after a server has reported that we exceeded its limits
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            default: None,
+            servers: Default::default(),
+            cooldown: Duration::from_secs(60)
+        }
+    }
+}
+
+struct TokenBucket {
+    rate: Rate,
+    tokens: f64,
+    updated: Instant
+}
+
+impl TokenBucket {
+    fn new(rate: Rate) -> Self {
+        TokenBucket {
+            rate: rate,
+            tokens: rate.burst,
+            updated: Instant::now()
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = if now > self.updated { now - self.updated } else { Duration::from_secs(0) };
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        self.tokens = (self.tokens + elapsed * self.rate.per_second).min(self.rate.burst);
+        self.updated = now;
+    }
+
+    fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - self.tokens) / self.rate.per_second;
+            Err(Duration::new(wait as u64, (wait.fract() * 1e9) as u32))
+        }
+    }
+}
+
+pub struct RateLimiter {
+    limits: RateLimits,
+    buckets: HashMap<String, TokenBucket>,
+    paused: HashMap<String, (Instant, ExpiryHandle)>, // Servers in cool-down, until when
+    cooldowns: ExpiryHandler<String> // Ends the cool-downs
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        RateLimiter {
+            limits: limits,
+            buckets: Default::default(),
+            paused: Default::default(),
+            cooldowns: ExpiryHandler::new(Duration::from_millis(COOLDOWN_TIMER_RESOLUTION_MS))
+        }
+    }
+
+    pub fn cooldown(&self) -> Duration {
+        self.limits.cooldown
+    }
+
+    // Stop sending queries to the server for the cool-down period
+    pub fn pause(&mut self, server: &str) {
+        let until = Instant::now() + self.limits.cooldown;
+        let handle = self.cooldowns.add(String::from(server), self.limits.cooldown);
+        if let Some((_, previous)) = self.paused.insert(String::from(server), (until, handle)) {
+            self.cooldowns.cancel(previous);
+        }
+    }
+
+    fn rate(&self, server: &str) -> Option<Rate> {
+        self.limits.servers.get(server).cloned().or(self.limits.default)
+    }
+
+    // Spend one query of the server's budget. Returns the time until budget is available otherwise.
+    pub fn acquire(&mut self, server: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        {
+            let paused = &mut self.paused;
+            self.cooldowns.clean(&mut |server: &String| {
+                paused.remove(server);
+            });
+        }
+        if let Some(&(until, _)) = self.paused.get(server) {
+            // Rounded up to the timer resolution
+            return Err(if until > now { until - now } else { Duration::from_millis(COOLDOWN_TIMER_RESOLUTION_MS) });
+        }
+        let rate = match self.rate(server) {
+            None => return Ok(()),
+            Some(r) => r
+        };
+        if !self.buckets.contains_key(server) {
+            self.buckets.insert(String::from(server), TokenBucket::new(rate));
+        }
+        self.buckets.get_mut(server).unwrap().take(now)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct ConcurrencyLimits {
+    pub default: Option<usize>, // Applies to servers without their own cap, unlimited if none
+    pub servers: HashMap<String, usize> // Caps by server name or IP address
+}
+
+pub struct ConcurrencyLimiter {
+    limits: ConcurrencyLimits,
+    active: HashMap<String, usize>
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(limits: ConcurrencyLimits) -> Self {
+        ConcurrencyLimiter {
+            limits: limits,
+            active: Default::default()
+        }
+    }
+
+    // The default cap only applies to the first key, which identifies the server
+    fn cap(&self, index: usize, key: &String) -> Option<usize> {
+        match self.limits.servers.get(key) {
+            Some(c) => Some(*c),
+            None if index == 0 => self.limits.default,
+            None => None
+        }
+    }
+
+    pub fn has_capacity(&self, keys: &[String]) -> bool {
+        for (index, key) in keys.iter().enumerate() {
+            if let Some(cap) = self.cap(index, key) {
+                if *self.active.get(key).unwrap_or(&0) >= cap {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    pub fn acquire(&mut self, keys: &[String]) {
+        for key in keys.iter() {
+            *self.active.entry(key.clone()).or_insert(0) += 1;
+        }
+    }
+
+    pub fn release(&mut self, keys: &[String]) {
+        for key in keys.iter() {
+            let remove = match self.active.get_mut(key) {
+                Some(count) => {
+                    *count -= 1;
+                    *count == 0
+                },
+                None => false
+            };
+            if remove {
+                self.active.remove(key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(server: &str, address: &str) -> Vec<String> {
+        vec![String::from(server), String::from(address)]
+    }
+
+    #[test]
+    fn parse_rate() {
+        let rate = Rate::from_str("2.5").unwrap();
+        assert_eq!((rate.per_second, rate.burst), (2.5, 3.0));
+        let rate = Rate::from_str("0.5:4").unwrap();
+        assert_eq!((rate.per_second, rate.burst), (0.5, 4.0));
+        let rate = Rate::from_str("0.2").unwrap();
+        assert_eq!((rate.per_second, rate.burst), (0.2, 1.0));
+        for s in ["0", "-1", "1:0.5", "x", "1:x", "NaN", ""].iter() {
+            assert!(Rate::from_str(s).is_err(), "{} accepted", s);
+        }
+    }
+
+    #[test]
+    fn token_bucket() {
+        let mut bucket = TokenBucket::new(Rate::from_str("2:2").unwrap());
+        let start = bucket.updated;
+        assert!(bucket.take(start).is_ok());
+        assert!(bucket.take(start).is_ok());
+        assert_eq!(bucket.take(start), Err(Duration::from_millis(500)));
+        // Half a second refills one token
+        let later = start + Duration::from_millis(500);
+        assert!(bucket.take(later).is_ok());
+        assert!(bucket.take(later).is_err());
+        // The burst is not exceeded after a long pause
+        let much_later = later + Duration::from_secs(60);
+        assert!(bucket.take(much_later).is_ok());
+        assert!(bucket.take(much_later).is_ok());
+        assert!(bucket.take(much_later).is_err());
+    }
+
+    #[test]
+    fn rate_limiter() {
+        let mut limits = RateLimits::default();
+        limits.servers.insert(String::from("whois.example"), Rate::from_str("1:1").unwrap());
+        let mut limiter = RateLimiter::new(limits);
+        assert!(limiter.acquire("whois.example").is_ok());
+        assert!(limiter.acquire("whois.example").is_err());
+        // Servers without a rate are unlimited if there is no default
+        for _ in 0..100 {
+            assert!(limiter.acquire("whois.other").is_ok());
+        }
+    }
+
+    #[test]
+    fn cooldown() {
+        let mut limiter = RateLimiter::new(RateLimits::default());
+        assert!(limiter.acquire("whois.example").is_ok());
+        limiter.pause("whois.example");
+        let wait = limiter.acquire("whois.example").unwrap_err();
+        assert!(wait > Duration::from_secs(59) && wait <= Duration::from_secs(60));
+        // Other servers are not affected
+        assert!(limiter.acquire("whois.other").is_ok());
+    }
+
+    #[test]
+    fn concurrency_limiter() {
+        let mut limits = ConcurrencyLimits::default();
+        limits.default = Some(2);
+        limits.servers.insert(String::from("whois.small"), 1);
+        limits.servers.insert(String::from("192.0.2.1"), 1);
+        let mut limiter = ConcurrencyLimiter::new(limits);
+
+        let small = keys("whois.small", "198.51.100.1");
+        assert!(limiter.has_capacity(&small));
+        limiter.acquire(&small);
+        assert!(!limiter.has_capacity(&small));
+        limiter.release(&small);
+        assert!(limiter.has_capacity(&small));
+
+        // The default cap applies to the server, not to its address
+        let other = keys("whois.other", "198.51.100.1");
+        limiter.acquire(&other);
+        limiter.acquire(&other);
+        assert!(!limiter.has_capacity(&other));
+        assert!(limiter.has_capacity(&keys("whois.third", "198.51.100.1")));
+
+        // Caps by address apply to all servers sharing it
+        let first = keys("whois.first", "192.0.2.1");
+        limiter.acquire(&first);
+        assert!(!limiter.has_capacity(&keys("whois.second", "192.0.2.1")));
+        limiter.release(&first);
+        assert!(limiter.has_capacity(&keys("whois.second", "192.0.2.1")));
+        assert!(limiter.active.get("192.0.2.1").is_none());
+    }
+}